@@ -2,11 +2,13 @@ use super::error::pretty_parse;
 use super::exp::Exp;
 use super::helper::{did_to_canister_info, FileSource, MyHelper};
 use super::token::{ParserError, Tokenizer};
-use super::utils::{get_dfx_hsm_pin, resolve_path};
+use super::utils::{
+    decrypt_pem, get_dfx_hsm_pin, glob_match, num_cmp, resolve_path, seed_to_secret_key,
+    str_to_principal, stringify, type_of,
+};
 use anyhow::{anyhow, Context};
 use candid::{types::value::IDLValue, Principal, TypeEnv};
-use candid_parser::configs::Configs;
-use pretty_assertions::{assert_eq, assert_ne};
+use pretty_assertions::Comparison;
 use std::ops::Range;
 use std::sync::Arc;
 use std::time::Instant;
@@ -17,10 +19,16 @@ pub struct Commands(pub Vec<(Command, Range<usize>)>);
 pub enum Command {
     Config(String),
     Show(Exp),
+    ShowEnv(Option<String>),
+    Print(Exp, bool),
+    Type(Exp),
+    Unset(Vec<String>),
+    Refresh(Exp),
     Let(String, Exp),
     Assert(BinOp, Exp, Exp),
     Import(String, Principal, Option<String>),
-    Load(Exp),
+    Load(Exp, Option<Vec<Exp>>),
+    Include(Exp),
     Identity(String, IdentityConfig),
     Func {
         name: String,
@@ -31,6 +39,11 @@ pub enum Command {
         cond: Exp,
         body: Vec<Command>,
     },
+    For {
+        var: String,
+        iter: Exp,
+        body: Vec<Command>,
+    },
     If {
         cond: Exp,
         then: Vec<Command>,
@@ -41,6 +54,8 @@ pub enum Command {
 pub enum IdentityConfig {
     Empty,
     Pem(String),
+    Seed(String),
+    EncryptedPem(String, Exp),
     Hsm { slot_index: usize, key_id: String },
 }
 #[allow(clippy::enum_variant_names)]
@@ -49,6 +64,23 @@ pub enum BinOp {
     Equal,
     SubEqual,
     NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+impl std::fmt::Display for BinOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BinOp::Equal => "==",
+            BinOp::SubEqual => "~=",
+            BinOp::NotEqual => "!=",
+            BinOp::Less => "<",
+            BinOp::LessEqual => "<=",
+            BinOp::Greater => ">",
+            BinOp::GreaterEqual => ">=",
+        })
+    }
 }
 
 impl Command {
@@ -58,7 +90,11 @@ impl Command {
                 if let Some(did) = &did {
                     let path = resolve_path(&helper.base_path, did);
                     let info = did_to_canister_info(did, FileSource::Path(&path), None)?;
-                    helper.canister_map.borrow_mut().0.insert(canister_id, info);
+                    helper
+                        .canister_map
+                        .borrow_mut()
+                        .map
+                        .insert(canister_id, info);
                 }
                 // TODO decide if it's a Service instead
                 helper.env.0.insert(id, IDLValue::Principal(canister_id));
@@ -74,34 +110,64 @@ impl Command {
             Command::Assert(op, left, right) => {
                 let left = left.eval(helper)?;
                 let right = right.eval(helper)?;
-                match op {
-                    BinOp::Equal => assert_eq!(left, right),
+                let ok = match op {
+                    BinOp::Equal | BinOp::NotEqual => (left == right) == matches!(op, BinOp::Equal),
                     BinOp::SubEqual => {
                         if let (IDLValue::Text(left), IDLValue::Text(right)) = (&left, &right) {
-                            assert!(left.contains(right));
+                            left.contains(right)
                         } else {
                             let l_ty = left.value_ty();
                             let r_ty = right.value_ty();
                             let env = TypeEnv::new();
-                            if let Ok(left) = left.annotate_type(false, &env, &r_ty) {
-                                assert_eq!(left, right);
-                            } else if let Ok(right) = right.annotate_type(false, &env, &l_ty) {
-                                assert_eq!(left, right);
+                            if let Ok(left) = left.clone().annotate_type(false, &env, &r_ty) {
+                                left == right
+                            } else if let Ok(right) =
+                                right.clone().annotate_type(false, &env, &l_ty)
+                            {
+                                left == right
                             } else {
-                                assert_eq!(left, right);
+                                left == right
                             }
                         }
                     }
-                    BinOp::NotEqual => assert_ne!(left, right),
+                    BinOp::Less | BinOp::LessEqual | BinOp::Greater | BinOp::GreaterEqual => {
+                        use std::cmp::Ordering::*;
+                        match (&op, num_cmp(&left, &right)?) {
+                            (_, None) => false,
+                            (BinOp::Less, Some(ord)) => ord == Less,
+                            (BinOp::LessEqual, Some(ord)) => ord != Greater,
+                            (BinOp::Greater, Some(ord)) => ord == Greater,
+                            (_, Some(ord)) => ord != Less,
+                        }
+                    }
+                };
+                let mut stats = helper.test_stats.borrow_mut();
+                if ok {
+                    stats.passed += 1;
+                } else {
+                    let mut msg = format!(
+                        "assertion failed: left {op} right\n  left: {}\n right: {}",
+                        stringify(&left)?,
+                        stringify(&right)?
+                    );
+                    stats.failed.push(msg.clone());
+                    if matches!(op, BinOp::Equal | BinOp::SubEqual) {
+                        msg.push_str(&format!("\n{}", Comparison::new(&left, &right)));
+                    }
+                    if helper.keep_going {
+                        eprintln!("{msg}");
+                    } else {
+                        return Err(anyhow!(msg));
+                    }
                 }
             }
             Command::Config(conf) => {
                 if conf.ends_with(".toml") {
                     let path = resolve_path(&helper.base_path, &conf);
                     let conf = std::fs::read_to_string(path)?;
-                    helper.config = conf.parse::<Configs>()?;
+                    helper.set_config(&conf)?;
                 } else {
-                    helper.config = conf.parse::<Configs>()?;
+                    helper.set_config(&conf)?;
                 }
             }
             Command::Show(val) => {
@@ -115,6 +181,48 @@ impl Command {
                     println!("{:>width$}", format!("({duration:.2?})"), width = width);
                 }
             }
+            // existing scripts may show a variable named `show`
+            Command::ShowEnv(None) if helper.env.0.contains_key("show") => {
+                Command::Show(Exp::Path("show".to_string(), Vec::new())).run(helper)?;
+            }
+            Command::ShowEnv(pattern) => {
+                for (name, v) in helper.env.0.iter() {
+                    if pattern.as_ref().is_none_or(|p| glob_match(p, name)) {
+                        println!("{name} = {}", stringify(v)?);
+                    }
+                }
+            }
+            Command::Print(val, newline) => {
+                use std::io::Write;
+                let v = val.eval(helper)?;
+                let mut out = std::io::stdout();
+                if newline {
+                    writeln!(out, "{}", stringify(&v)?)?;
+                } else {
+                    write!(out, "{}", stringify(&v)?)?;
+                    out.flush()?;
+                }
+            }
+            Command::Type(val) => {
+                let v = val.eval(helper)?;
+                println!("{}", type_of(&v));
+            }
+            Command::Unset(ids) => {
+                if let Some(id) = ids.iter().find(|id| !helper.env.0.contains_key(*id)) {
+                    return Err(anyhow!("{id} is not bound"));
+                }
+                for id in ids {
+                    helper.env.0.remove(&id);
+                }
+            }
+            Command::Refresh(e) => {
+                let id = match e.eval(helper)? {
+                    IDLValue::Principal(id) | IDLValue::Service(id) => id,
+                    IDLValue::Text(id) => str_to_principal(&id, helper)?,
+                    _ => return Err(anyhow!("refresh expects a canister id")),
+                };
+                helper.canister_map.borrow_mut().evict(&id);
+            }
             Command::Identity(id, config) => {
                 use ic_agent::identity::{BasicIdentity, Identity, Secp256k1Identity};
                 let identity: Arc<dyn Identity> = match &config {
@@ -137,11 +245,34 @@ impl Command {
                     }
                     IdentityConfig::Pem(pem_path) => {
                         let pem_path = resolve_path(&helper.base_path, pem_path);
+                        if std::fs::read_to_string(&pem_path)
+                            .is_ok_and(|pem| pem.contains("ENCRYPTED PRIVATE KEY"))
+                        {
+                            return Err(anyhow!("{} is encrypted, use `identity {id} \"{}\" with passphrase = <text>`", pem_path.display(), pem_path.display()));
+                        }
                         match Secp256k1Identity::from_pem_file(&pem_path) {
                             Ok(identity) => Arc::from(identity),
                             Err(_) => Arc::from(BasicIdentity::from_pem_file(&pem_path)?),
                         }
                     }
+                    IdentityConfig::EncryptedPem(pem_path, pass) => {
+                        let pass = match pass.clone().eval(helper)? {
+                            IDLValue::Opt(pass) => *pass,
+                            IDLValue::None => return Err(anyhow!("passphrase is not set")),
+                            v => v,
+                        };
+                        let IDLValue::Text(pass) = pass else {
+                            return Err(anyhow!("passphrase needs to be a text"));
+                        };
+                        let pem_path = resolve_path(&helper.base_path, pem_path);
+                        let pem = std::fs::read(&pem_path)
+                            .with_context(|| format!("Cannot read {pem_path:?}"))?;
+                        decrypt_pem(&pem, &pass)
+                            .with_context(|| format!("Cannot load {pem_path:?}"))?
+                    }
+                    IdentityConfig::Seed(phrase) => Arc::from(Secp256k1Identity::from_private_key(
+                        seed_to_secret_key(phrase)?,
+                    )),
                     IdentityConfig::Empty => match helper.identity_map.0.get(&id) {
                         Some(identity) => identity.clone(),
                         None => Arc::from(BasicIdentity::from_signing_key(
@@ -160,12 +291,22 @@ impl Command {
                 helper.current_identity = id.to_string();
                 helper.env.0.insert(id, IDLValue::Principal(sender));
             }
-            Command::Load(e) => {
+            Command::Load(e, args) => {
                 // TODO check for infinite loop
                 // Note that it's a bit tricky to make load as a built-in function, as it requires mutable access to helper.
                 let IDLValue::Text(file) = e.eval(helper)? else {
                     return Err(anyhow!("load needs to be a file path"));
                 };
+                let args = match args {
+                    Some(args) => {
+                        let mut res = Vec::with_capacity(args.len());
+                        for arg in args.into_iter() {
+                            res.push(arg.eval(helper)?);
+                        }
+                        Some(res)
+                    }
+                    None => None,
+                };
                 let (file, fail_safe) = if file.ends_with('?') {
                     (file.trim_end_matches('?'), true)
                 } else {
@@ -185,6 +326,15 @@ impl Command {
                 let script =
                     shellexpand::env(&script).map_err(|e| crate::token::error2(e, 0..0))?;
                 let cmds = pretty_parse::<Commands>(file, &script)?;
+                // With arguments, the script runs in a new scope with the arguments bound to `_1`, `_2`, ...
+                let mut scope = args.map(|args| {
+                    let mut helper = helper.spawn();
+                    for (i, v) in args.into_iter().enumerate() {
+                        helper.env.0.insert(format!("_{}", i + 1), v);
+                    }
+                    helper
+                });
+                let helper = scope.as_mut().unwrap_or(helper);
                 helper.base_path = path.parent().unwrap().to_path_buf();
                 for (cmd, pos) in cmds.0.into_iter() {
                     if helper.verbose {
@@ -194,6 +344,16 @@ impl Command {
                 }
                 helper.base_path = old_base;
             }
+            Command::Include(e) => {
+                let IDLValue::Text(file) = e.eval(helper)? else {
+                    return Err(anyhow!("include needs to be a file path"));
+                };
+                let path = resolve_path(&helper.base_path, &file);
+                let path = path.canonicalize().unwrap_or(path);
+                if helper.included.insert(path) {
+                    Command::Load(Exp::Text(file), None).run(helper)?;
+                }
+            }
             Command::If { cond, then, else_ } => {
                 let IDLValue::Bool(cond) = cond.eval(helper)? else {
                     return Err(anyhow!("if condition is not a boolean expression"));
@@ -219,6 +379,20 @@ impl Command {
                     cmd.clone().run(helper)?;
                 }
             },
+            Command::For { var, iter, body } => {
+                let items = match iter.eval(helper)? {
+                    IDLValue::Vec(vs) => vs,
+                    IDLValue::Blob(b) => b.into_iter().map(IDLValue::Nat8).collect(),
+                    _ => return Err(anyhow!("for expects a vec")),
+                };
+                let mut helper = helper.spawn();
+                for item in items {
+                    helper.env.0.insert(var.clone(), item);
+                    for cmd in body.iter() {
+                        cmd.clone().run(&mut helper)?;
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -258,3 +432,55 @@ fn bind_value(helper: &mut MyHelper, id: String, v: IDLValue, is_call: bool, dis
         helper.env.0.insert(id, v);
     }
 }
+
+#[test]
+fn test_for_loop() -> anyhow::Result<()> {
+    let mut helper = crate::helper::test_helper();
+    let run = |helper: &mut MyHelper, s: &str| -> anyhow::Result<()> {
+        for (cmd, _) in s.parse::<Commands>().unwrap().0 {
+            cmd.run(helper)?;
+        }
+        Ok(())
+    };
+    // Iterates in order, and bindings persist across iterations within the loop scope.
+    run(
+        &mut helper,
+        "let n = 0; for x in vec { 1; 2; 3 } { let n = add(n, 1); assert x == n }",
+    )?;
+    run(
+        &mut helper,
+        r#"let i = 0; for b in blob "\01\02" { let i = add(i, 1); assert b ~= i }"#,
+    )?;
+    assert_eq!(helper.test_stats.borrow().passed, 5);
+    // Loop variables and bindings do not leak out of the loop.
+    assert_eq!(
+        helper.env.0.get("n"),
+        Some(&IDLValue::Number("0".to_string()))
+    );
+    assert!(!helper.env.0.contains_key("x"));
+    assert!(!helper.env.0.contains_key("b"));
+    assert!(run(&mut helper, "for x in 42 { x }").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_keyword_names() -> anyhow::Result<()> {
+    let mut helper = crate::helper::test_helper();
+    let script = "function print(x) { add(x, 1) }; let y = print(41); assert y == 42; print(1)";
+    for (cmd, _) in script.parse::<Commands>().unwrap().0 {
+        cmd.run(&mut helper)?;
+    }
+    assert_eq!(helper.test_stats.borrow().passed, 1);
+    assert_eq!(
+        helper.env.0.get("_"),
+        Some(&IDLValue::Number("2".to_string()))
+    );
+    for (cmd, _) in "let show = 7; show".parse::<Commands>().unwrap().0 {
+        cmd.run(&mut helper)?;
+    }
+    assert_eq!(
+        helper.env.0.get("_"),
+        Some(&IDLValue::Number("7".to_string()))
+    );
+    Ok(())
+}
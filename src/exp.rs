@@ -3,8 +3,8 @@ use super::helper::{find_init_args, MyHelper, OfflineOutput};
 use super::selector::{project, Selector};
 use super::token::{ParserError, Tokenizer};
 use super::utils::{
-    args_to_value, as_u32, cast_type, get_effective_canister_id, get_field, resolve_path,
-    str_to_principal,
+    args_to_value, as_blob, as_u32, cast_type, get_effective_canister_id, get_field, resolve_path,
+    runtime, str_to_principal, type_of, with_retry, with_timeout,
 };
 use anyhow::{anyhow, Context, Result};
 use candid::{
@@ -27,6 +27,7 @@ pub enum Exp {
     },
     ParCall {
         calls: Vec<FuncCall>,
+        keep_going: bool,
     },
     Decode {
         method: Option<Method>,
@@ -34,6 +35,7 @@ pub enum Exp {
     },
     Apply(String, Vec<Exp>),
     Fail(Box<Exp>),
+    Match(Box<Exp>, Vec<MatchArm>),
     // from IDLValue without the infered types
     Bool(bool),
     Null,
@@ -49,22 +51,35 @@ pub enum Exp {
     Service(Principal),
     Func(Principal, String),
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Method {
     pub canister: String,
     pub method: String,
+    /// Method name given as `canister.[exp]`, evaluated to text before the call.
+    pub method_exp: Option<Box<Exp>>,
+    /// Canister given as `(exp).method`, evaluated to a principal before the call.
+    pub canister_exp: Option<Box<Exp>>,
 }
 #[derive(Debug, Clone)]
 pub enum CallMode {
     Call,
+    Async,
     Encode,
-    Proxy(String),
+    EncodeHex,
+    Proxy(String, Option<Box<Exp>>),
 }
 #[derive(Debug, Clone)]
 pub struct FuncCall {
     pub method: Method,
     pub args: Vec<Exp>,
 }
+/// A `match` arm. `label` is `None` for the wildcard `_`, which binds `var` to the whole value instead of the payload.
+#[derive(Debug, Clone)]
+pub struct MatchArm {
+    pub label: Option<Label>,
+    pub var: Option<String>,
+    pub body: Exp,
+}
 #[derive(Debug, Clone)]
 pub struct Field {
     pub id: Label,
@@ -196,6 +211,142 @@ impl Exp {
                         }
                         _ => return Err(anyhow!("neuron_account expects (principal, nonce)")),
                     },
+                    "principal_to_text" => match args.as_slice() {
+                        [IDLValue::Principal(id) | IDLValue::Service(id)] => {
+                            IDLValue::Text(id.to_text())
+                        }
+                        _ => return Err(anyhow!("principal_to_text expects principal")),
+                    },
+                    "text_to_principal" => match args.as_slice() {
+                        [IDLValue::Text(id)] => IDLValue::Principal(str_to_principal(id, helper)?),
+                        _ => return Err(anyhow!("text_to_principal expects text")),
+                    },
+                    "e8s_to_icp" => match args.as_slice() {
+                        [n] => {
+                            let IDLValue::Nat64(e8s) = cast_type(n.clone(), &TypeInner::Nat64.into())?
+                            else {
+                                unreachable!()
+                            };
+                            IDLValue::Text(crate::utils::e8s_to_icp(e8s))
+                        }
+                        _ => return Err(anyhow!("e8s_to_icp expects a number")),
+                    },
+                    "icp_to_e8s" => match args.as_slice() {
+                        [IDLValue::Text(icp)] => IDLValue::Nat64(crate::utils::icp_to_e8s(icp)?),
+                        _ => return Err(anyhow!("icp_to_e8s expects text")),
+                    },
+                    "anonymous" => match args.as_slice() {
+                        [] => IDLValue::Principal(Principal::anonymous()),
+                        _ => return Err(anyhow!("anonymous expects no arguments")),
+                    },
+                    "is_anonymous" => match args.as_slice() {
+                        [IDLValue::Principal(id)] => IDLValue::Bool(*id == Principal::anonymous()),
+                        _ => return Err(anyhow!("is_anonymous expects principal")),
+                    },
+                    "icrc1_transfer_arg" => match args.as_slice() {
+                        [IDLValue::Record(fs)] => crate::icrc::transfer_arg(fs)?,
+                        _ => return Err(anyhow!("icrc1_transfer_arg expects a record")),
+                    },
+                    "icrc1_check" => match args.as_slice() {
+                        [result] => crate::icrc::check_result(result)?,
+                        _ => return Err(anyhow!("icrc1_check expects a result variant")),
+                    },
+                    "icrc_account" => match args.as_slice() {
+                        [IDLValue::Principal(owner)] => {
+                            IDLValue::Text(crate::icrc::encode_account(owner, None)?)
+                        }
+                        [IDLValue::Principal(owner), sub] => {
+                            let sub = as_blob(sub.clone())?;
+                            IDLValue::Text(crate::icrc::encode_account(owner, Some(&sub))?)
+                        }
+                        _ => return Err(anyhow!("icrc_account expects (principal, subaccount)")),
+                    },
+                    "parse_icrc_account" => match args.as_slice() {
+                        [IDLValue::Text(text)] => crate::icrc::decode_account(text)?,
+                        _ => return Err(anyhow!("parse_icrc_account expects text")),
+                    },
+                    "principal_from_blob" => match args.as_slice() {
+                        [blob] => IDLValue::Principal(
+                            Principal::try_from_slice(&as_blob(blob.clone())?)
+                                .map_err(|e| anyhow!("not a valid principal: {e}"))?,
+                        ),
+                        _ => return Err(anyhow!("principal_from_blob expects blob")),
+                    },
+                    "principal_to_blob" => match args.as_slice() {
+                        [IDLValue::Principal(id) | IDLValue::Service(id)] => {
+                            IDLValue::Blob(id.as_slice().to_vec())
+                        }
+                        _ => return Err(anyhow!("principal_to_blob expects principal")),
+                    },
+                    "parse_pem" => match args.as_slice() {
+                        [IDLValue::Text(pem)] => {
+                            use ic_agent::identity::{BasicIdentity, Identity, Secp256k1Identity};
+                            let (key_type, identity): (_, Box<dyn Identity>) =
+                                match Secp256k1Identity::from_pem(pem.as_bytes()) {
+                                    Ok(identity) => ("secp256k1", Box::new(identity)),
+                                    Err(_) => (
+                                        "ed25519",
+                                        Box::new(BasicIdentity::from_pem(pem.as_bytes())?),
+                                    ),
+                                };
+                            let public_key = identity
+                                .public_key()
+                                .ok_or_else(|| anyhow!("no public key found"))?;
+                            let principal = identity.sender().map_err(|e| anyhow!("{}", e))?;
+                            let mut fs = vec![
+                                IDLField {
+                                    id: Label::Named("key_type".to_string()),
+                                    val: IDLValue::Text(key_type.to_string()),
+                                },
+                                IDLField {
+                                    id: Label::Named("public_key".to_string()),
+                                    val: IDLValue::Blob(public_key),
+                                },
+                                IDLField {
+                                    id: Label::Named("principal_id".to_string()),
+                                    val: IDLValue::Principal(principal),
+                                },
+                            ];
+                            fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+                            IDLValue::Record(fs)
+                        }
+                        _ => return Err(anyhow!("parse_pem expects the PEM content as text")),
+                    },
+                    "env" => match args.as_slice() {
+                        [IDLValue::Text(name)] => match std::env::var(name) {
+                            Ok(v) => IDLValue::Opt(Box::new(IDLValue::Text(v))),
+                            Err(_) => IDLValue::None,
+                        },
+                        [IDLValue::Text(name), default] => match std::env::var(name) {
+                            Ok(v) => IDLValue::Text(v),
+                            Err(_) => default.clone(),
+                        },
+                        _ => return Err(anyhow!("env expects (name) or (name, default)")),
+                    },
+                    "timestamp_ns" | "timestamp_ms" => match args.as_slice() {
+                        [] => {
+                            let now = std::time::SystemTime::now()
+                                .duration_since(std::time::UNIX_EPOCH)?;
+                            IDLValue::Nat64(if func == "timestamp_ns" {
+                                now.as_nanos() as u64
+                            } else {
+                                now.as_millis() as u64
+                            })
+                        }
+                        _ => return Err(anyhow!("{func} expects no arguments")),
+                    },
+                    "sleep" => match args.as_slice() {
+                        [millis] => {
+                            let Ok(IDLValue::Nat64(millis)) =
+                                cast_type(millis.clone(), &TypeInner::Nat64.into())
+                            else {
+                                return Err(anyhow!("sleep expects a non-negative number of milliseconds"));
+                            };
+                            std::thread::sleep(std::time::Duration::from_millis(millis));
+                            IDLValue::Null
+                        }
+                        _ => return Err(anyhow!("sleep expects milliseconds")),
+                    },
                     "replica_url" => match args.as_slice() {
                         [] => IDLValue::Text(helper.agent_url.clone()),
                         _ => return Err(anyhow!("replica_url expects no arguments")),
@@ -219,6 +370,241 @@ impl Exp {
                             }
                         }
                     }
+                    "subnet_metrics" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(id)] => {
+                            use crate::utils::{fetch_state_path, parse_state_path};
+                            let path = parse_state_path(&[
+                                IDLValue::Text("subnet".to_string()),
+                                IDLValue::Principal(*id),
+                                IDLValue::Text("metrics".to_string()),
+                            ])?;
+                            fetch_state_path(&helper.agent, path)?
+                        }
+                        _ => return Err(anyhow!("subnet_metrics expects a subnet id")),
+                    },
+                    "read_state" => {
+                        use crate::offline::{output_message, Ingress, IngressWithStatus};
+                        use crate::utils::{parse_state_path, sign_state_path};
+                        let path = match args.as_slice() {
+                            [IDLValue::Text(_), ..] => parse_state_path(args.as_slice())?,
+                            [IDLValue::Principal(effective), IDLValue::Text(_), ..] => {
+                                let mut path = parse_state_path(&args[1..])?;
+                                path.effective_id = Some(*effective);
+                                path
+                            }
+                            _ => {
+                                return Err(anyhow!(
+                                "read_state expects ([effective_id,] prefix, principal, path, ...)"
+                            ))
+                            }
+                        };
+                        let identity = helper
+                            .identity_map
+                            .0
+                            .get(&helper.current_identity)
+                            .ok_or_else(|| anyhow!("Cannot find the current identity"))?;
+                        let (call_type, effective_id, content) =
+                            sign_state_path(identity.as_ref(), path)?;
+                        let message = IngressWithStatus {
+                            ingress: Ingress {
+                                call_type,
+                                request_id: None,
+                                content: hex::encode(content),
+                                effective_id: Some(effective_id),
+                            },
+                            request_status: None,
+                        };
+                        helper.messages.borrow_mut().push(message.clone());
+                        output_message(&message, helper.offline.as_ref().unwrap())?;
+                        IDLValue::Null
+                    }
+                    "poll_status" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(id), IDLValue::Blob(request_id)] => {
+                            crate::utils::request_status(&helper.agent, *id, request_id)?
+                        }
+                        [IDLValue::Principal(id), IDLValue::Text(request_id)] => {
+                            let hex = request_id.trim_start_matches("0x");
+                            let request_id = hex::decode(hex)
+                                .map_err(|e| anyhow!("request_id is not hex: {e}"))?;
+                            crate::utils::request_status(&helper.agent, *id, &request_id)?
+                        }
+                        _ => return Err(anyhow!("poll_status expects (canister_id, request_id)")),
+                    },
+                    "wait_module_hash" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(id), expected, timeout_ms] => {
+                            let expected = match expected {
+                                IDLValue::Text(hash) => hex::decode(hash)
+                                    .map_err(|e| anyhow!("expected hash is not hex: {e}"))?,
+                                _ => as_blob(expected.clone())?,
+                            };
+                            let IDLValue::Nat64(timeout_ms) =
+                                cast_type(timeout_ms.clone(), &TypeInner::Nat64.into())?
+                            else {
+                                unreachable!()
+                            };
+                            crate::utils::wait_module_hash(
+                                &helper.agent,
+                                *id,
+                                &expected,
+                                std::time::Duration::from_millis(timeout_ms),
+                            )?;
+                            IDLValue::Bool(true)
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "wait_module_hash expects (canister, expected_hash, timeout_ms)"
+                            ))
+                        }
+                    },
+                    "icrc1_balance" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(ledger), IDLValue::Principal(owner), rest @ ..]
+                            if rest.len() <= 1 =>
+                        {
+                            let sub = match rest.first() {
+                                Some(IDLValue::Opt(sub)) => Some(as_blob(*sub.clone())?),
+                                Some(IDLValue::None | IDLValue::Null) | None => None,
+                                Some(sub) => Some(as_blob(sub.clone())?),
+                            };
+                            let balance =
+                                crate::icrc::balance_of(&helper.agent, ledger, *owner, sub)?;
+                            IDLValue::Nat(balance)
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "icrc1_balance expects (ledger, owner principal, optional subaccount)"
+                            ))
+                        }
+                    },
+                    "icrc3_blocks" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(ledger), start, length] => crate::icrc::get_blocks(
+                            &helper.agent,
+                            ledger,
+                            start.clone(),
+                            length.clone(),
+                        )?,
+                        _ => return Err(anyhow!("icrc3_blocks expects (ledger, start, length)")),
+                    },
+                    "submit_proposal" => match args.as_slice() {
+                        [IDLValue::Record(fs)] => {
+                            use crate::nns::{make_proposal_arg, proposal_id, GOVERNANCE_CANISTER_ID};
+                            let arg = make_proposal_arg(fs)?;
+                            let res =
+                                call_method(helper, GOVERNANCE_CANISTER_ID, "manage_neuron", arg)?;
+                            if helper.offline.is_some() {
+                                IDLValue::Null
+                            } else {
+                                proposal_id(&res)?
+                            }
+                        }
+                        _ => return Err(anyhow!("submit_proposal expects record {{ neuron_id; title; summary; url; action }}")),
+                    },
+                    "proposal_info" if helper.offline.is_none() => match args.as_slice() {
+                        [id] => {
+                            use crate::nns::{proposal_info, GOVERNANCE_CANISTER_ID};
+                            let id = cast_type(id.clone(), &TypeInner::Nat64.into())
+                                .map_err(|_| anyhow!("proposal_info expects a nat64 proposal id"))?;
+                            let res = call_method(
+                                helper,
+                                GOVERNANCE_CANISTER_ID,
+                                "get_proposal_info",
+                                id.clone(),
+                            )?;
+                            proposal_info(&id, res)?
+                        }
+                        _ => return Err(anyhow!("proposal_info expects a proposal id")),
+                    },
+                    "sns_canisters" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(root)] => {
+                            let res = call_method(
+                                helper,
+                                &root.to_text(),
+                                "list_sns_canisters",
+                                IDLValue::Record(vec![]),
+                            )?;
+                            crate::nns::sns_canisters(res)?
+                        }
+                        _ => return Err(anyhow!("sns_canisters expects the SNS root canister id")),
+                    },
+                    "create_canister" => {
+                        use crate::management::*;
+                        let arg = match args.as_slice() {
+                            [] => create_canister_arg(None),
+                            [settings @ IDLValue::Record(_)] => {
+                                create_canister_arg(Some(settings.clone()))
+                            }
+                            _ => return Err(anyhow!("create_canister expects optional settings")),
+                        };
+                        // Ingress messages cannot call create_canister, so use the provisional API,
+                        // which is only available outside of mainnet.
+                        if helper.is_mainnet() {
+                            return Err(anyhow!(
+                                "create_canister is not available on mainnet. Use a wallet with \
+                                 `call as <wallet> with cycles = <amount> ic.create_canister(...)`, \
+                                 or `notify_create_canister` of the cycles minting canister"
+                            ));
+                        }
+                        let method = "provisional_create_canister_with_cycles";
+                        let res = call_method(helper, MANAGEMENT_CANISTER_ID, method, arg)?;
+                        if helper.offline.is_some() {
+                            IDLValue::Null
+                        } else {
+                            created_canister_id(&res)?
+                        }
+                    }
+                    "install_code" => match args.as_slice() {
+                        [IDLValue::Principal(id), wasm, IDLValue::Text(mode), rest @ ..]
+                            if rest.len() <= 1 =>
+                        {
+                            use crate::management::*;
+                            let wasm = as_blob(wasm.clone())?;
+                            let arg = rest.first().map(|v| as_blob(v.clone())).transpose()?;
+                            let arg = install_code_arg(*id, wasm, mode, arg)?;
+                            call_method(helper, MANAGEMENT_CANISTER_ID, "install_code", arg)?
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "install_code expects (canister id, wasm blob, mode, optional arg blob)"
+                            ))
+                        }
+                    },
+                    "start_canister" | "stop_canister" => match args.as_slice() {
+                        [IDLValue::Principal(id)] => {
+                            use crate::management::{canister_id_arg, MANAGEMENT_CANISTER_ID};
+                            let arg = canister_id_arg(*id);
+                            call_method(helper, MANAGEMENT_CANISTER_ID, &func, arg)?
+                        }
+                        _ => return Err(anyhow!("{func} expects a canister id")),
+                    },
+                    "canister_status" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(id)] => {
+                            use crate::management::{canister_id_arg, MANAGEMENT_CANISTER_ID};
+                            let arg = canister_id_arg(*id);
+                            call_method(helper, MANAGEMENT_CANISTER_ID, "canister_status", arg)?
+                        }
+                        _ => return Err(anyhow!("canister_status expects a canister id")),
+                    },
+                    // `__get_cycles` from profiling counts instructions, so the balance comes from canister_status.
+                    "cycles_balance" if helper.offline.is_none() => match args.as_slice() {
+                        [IDLValue::Principal(id)] => {
+                            use crate::management::{canister_id_arg, MANAGEMENT_CANISTER_ID};
+                            let arg = canister_id_arg(*id);
+                            let res =
+                                call_method(helper, MANAGEMENT_CANISTER_ID, "canister_status", arg)?;
+                            match &res {
+                                IDLValue::Record(fs) => get_field(fs, "cycles").cloned(),
+                                _ => None,
+                            }
+                            .ok_or_else(|| anyhow!("unexpected canister_status response: {res}"))?
+                        }
+                        _ => return Err(anyhow!("cycles_balance expects a canister id")),
+                    },
+                    "icrc3_verify" => match args.as_slice() {
+                        [IDLValue::Vec(blocks)] => match crate::icrc::verify_chain(blocks)? {
+                            None => IDLValue::Bool(true),
+                            Some(id) => IDLValue::Nat(id),
+                        },
+                        _ => return Err(anyhow!("icrc3_verify expects a vec of blocks")),
+                    },
                     "file" => match args.as_slice() {
                         [IDLValue::Text(file)] => {
                             let path = resolve_path(&helper.base_path, file);
@@ -229,6 +615,45 @@ impl Exp {
                         }
                         _ => return Err(anyhow!("file expects file path")),
                     },
+                    "read_text" => match args.as_slice() {
+                        [IDLValue::Text(file)] => {
+                            let path = resolve_path(&helper.base_path, file);
+                            IDLValue::Text(
+                                std::fs::read_to_string(&path)
+                                    .with_context(|| format!("Cannot read {path:?} as text"))?,
+                            )
+                        }
+                        _ => return Err(anyhow!("read_text expects file path")),
+                    },
+                    "basename" | "dirname" => match args.as_slice() {
+                        [IDLValue::Text(path)] => {
+                            let path = std::path::Path::new(path);
+                            let res = if func == "basename" {
+                                path.file_name().map(|f| f.to_string_lossy())
+                            } else {
+                                path.parent().map(|p| p.to_string_lossy())
+                            };
+                            IDLValue::Text(res.unwrap_or_default().to_string())
+                        }
+                        _ => return Err(anyhow!("{func} expects a text path")),
+                    },
+                    "join_path" => match args.as_slice() {
+                        [IDLValue::Text(a), IDLValue::Text(b)] => {
+                            let path = std::path::Path::new(a).join(b);
+                            IDLValue::Text(path.to_string_lossy().to_string())
+                        }
+                        _ => return Err(anyhow!("join_path expects two text paths")),
+                    },
+                    "glob" => match args.as_slice() {
+                        [IDLValue::Text(pattern)] => {
+                            let files = crate::utils::glob_files(&helper.base_path, pattern);
+                            let files = files
+                                .into_iter()
+                                .map(|p| IDLValue::Text(p.to_string_lossy().to_string()));
+                            IDLValue::Vec(files.collect())
+                        }
+                        _ => return Err(anyhow!("glob expects a text pattern")),
+                    },
                     "gzip" => match args.as_slice() {
                         [IDLValue::Blob(blob)] => {
                             use libflate::gzip::Encoder;
@@ -240,6 +665,164 @@ impl Exp {
                         }
                         _ => return Err(anyhow!("gzip expects blob")),
                     },
+                    "gunzip" => match args.as_slice() {
+                        [IDLValue::Blob(blob)] => {
+                            use libflate::gzip::Decoder;
+                            use std::io::Read;
+                            let mut decoder = Decoder::new(blob.as_slice())
+                                .map_err(|e| anyhow!("not a valid gzip blob: {e}"))?;
+                            let mut result = Vec::new();
+                            decoder
+                                .read_to_end(&mut result)
+                                .map_err(|e| anyhow!("not a valid gzip blob: {e}"))?;
+                            IDLValue::Blob(result)
+                        }
+                        _ => return Err(anyhow!("gunzip expects blob")),
+                    },
+                    "crc32" => match args.as_slice() {
+                        [IDLValue::Text(str)] => {
+                            IDLValue::Blob(crc32fast::hash(str.as_bytes()).to_be_bytes().to_vec())
+                        }
+                        [blob] => {
+                            let bytes = as_blob(blob.clone())?;
+                            IDLValue::Blob(crc32fast::hash(&bytes).to_be_bytes().to_vec())
+                        }
+                        _ => return Err(anyhow!("crc32 expects blob or text")),
+                    },
+                    "from_json" => match args.as_slice() {
+                        [IDLValue::Text(json)] => crate::json::from_json(json)?,
+                        _ => return Err(anyhow!("from_json expects a JSON text")),
+                    },
+                    "to_json" => match args.as_slice() {
+                        [v] => IDLValue::Text(crate::json::to_json(v, crate::json::BlobFormat::Base64)?),
+                        [v, IDLValue::Record(fs)] => {
+                            let blob = match get_field(fs, "blob_format") {
+                                Some(IDLValue::Text(format)) => format.parse()?,
+                                Some(_) => return Err(anyhow!("blob_format expects a text")),
+                                None => crate::json::BlobFormat::Base64,
+                            };
+                            IDLValue::Text(crate::json::to_json(v, blob)?)
+                        }
+                        _ => return Err(anyhow!("to_json expects (value) or (value, record {{ blob_format }})")),
+                    },
+                    "msgpack_encode" => match args.as_slice() {
+                        [v] => IDLValue::Blob(crate::msgpack::encode(v)?),
+                        _ => return Err(anyhow!("msgpack_encode expects a value")),
+                    },
+                    "msgpack_decode" => match args.as_slice() {
+                        [blob] => crate::msgpack::decode(&as_blob(blob.clone())?)?,
+                        _ => return Err(anyhow!("msgpack_decode expects blob")),
+                    },
+                    "utf8_decode" => match args.as_slice() {
+                        [blob] => IDLValue::Text(
+                            String::from_utf8(as_blob(blob.clone())?)
+                                .map_err(|e| anyhow!("blob is not valid utf8: {e}"))?,
+                        ),
+                        _ => return Err(anyhow!("utf8_decode expects blob")),
+                    },
+                    "utf8_encode" => match args.as_slice() {
+                        [IDLValue::Text(str)] => IDLValue::Blob(str.as_bytes().to_vec()),
+                        _ => return Err(anyhow!("utf8_encode expects text")),
+                    },
+                    "merkle_root" | "merkle_proof" => match args.as_slice() {
+                        [IDLValue::Vec(vs), rest @ ..] if rest.len() <= 1 => {
+                            use crate::utils::{merkle_proof, merkle_root};
+                            let mut leaves = Vec::with_capacity(vs.len());
+                            for v in vs {
+                                leaves.push(as_blob(v.clone())?);
+                            }
+                            match (func.as_str(), rest) {
+                                ("merkle_root", []) => IDLValue::Blob(merkle_root(&leaves)?),
+                                ("merkle_proof", [index]) => {
+                                    let proof = merkle_proof(&leaves, as_u32(index)? as usize)?;
+                                    IDLValue::Vec(
+                                        proof
+                                            .into_iter()
+                                            .map(|(hash, is_left)| {
+                                                IDLValue::Record(vec![
+                                                    IDLField {
+                                                        id: Label::Named("hash".to_string()),
+                                                        val: IDLValue::Blob(hash),
+                                                    },
+                                                    IDLField {
+                                                        id: Label::Named("is_left".to_string()),
+                                                        val: IDLValue::Bool(is_left),
+                                                    },
+                                                ])
+                                            })
+                                            .collect(),
+                                    )
+                                }
+                                _ => {
+                                    return Err(anyhow!(
+                                        "merkle_root expects (vec blob) and merkle_proof expects (vec blob, index)"
+                                    ))
+                                }
+                            }
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "merkle_root expects (vec blob) and merkle_proof expects (vec blob, index)"
+                            ))
+                        }
+                    },
+                    "hmac_sha256" | "hmac_sha256_verify" => match args.as_slice() {
+                        [key, msg, rest @ ..] if rest.len() <= 1 => {
+                            use hmac::{Hmac, Mac};
+                            let key = as_blob(key.clone())?;
+                            let msg = match msg {
+                                IDLValue::Text(str) => str.as_bytes().to_vec(),
+                                msg => as_blob(msg.clone())?,
+                            };
+                            let mut mac = Hmac::<sha2::Sha256>::new_from_slice(&key)?;
+                            mac.update(&msg);
+                            match (func.as_str(), rest) {
+                                ("hmac_sha256", []) => {
+                                    IDLValue::Blob(mac.finalize().into_bytes().to_vec())
+                                }
+                                ("hmac_sha256_verify", [tag]) => {
+                                    IDLValue::Bool(mac.verify_slice(&as_blob(tag.clone())?).is_ok())
+                                }
+                                _ => {
+                                    return Err(anyhow!(
+                                        "hmac_sha256 expects (key, message) and hmac_sha256_verify expects (key, message, mac)"
+                                    ))
+                                }
+                            }
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "hmac_sha256 expects (key, message) and hmac_sha256_verify expects (key, message, mac)"
+                            ))
+                        }
+                    },
+                    "to_hex" => match args.as_slice() {
+                        [blob] => IDLValue::Text(hex::encode(as_blob(blob.clone())?)),
+                        _ => return Err(anyhow!("to_hex expects blob")),
+                    },
+                    "to_base64" => match args.as_slice() {
+                        [blob] => {
+                            use base64::{engine::general_purpose::STANDARD, Engine};
+                            IDLValue::Text(STANDARD.encode(as_blob(blob.clone())?))
+                        }
+                        _ => return Err(anyhow!("to_base64 expects blob")),
+                    },
+                    "from_base64" => match args.as_slice() {
+                        [IDLValue::Text(str)] => {
+                            use base64::{engine::general_purpose::STANDARD, Engine};
+                            IDLValue::Blob(STANDARD.decode(str).map_err(|e| {
+                                anyhow!("{str} is not a valid base64 string: {e}")
+                            })?)
+                        }
+                        _ => return Err(anyhow!("from_base64 expects text")),
+                    },
+                    "from_hex" => match args.as_slice() {
+                        [IDLValue::Text(str)] => IDLValue::Blob(
+                            hex::decode(str)
+                                .map_err(|e| anyhow!("{str} is not a valid hex string: {e}"))?,
+                        ),
+                        _ => return Err(anyhow!("from_hex expects text")),
+                    },
                     "exec" => match args.as_slice() {
                         [IDLValue::Text(cmd), ..] => {
                             use std::io::{BufRead, BufReader};
@@ -249,6 +832,8 @@ impl Exp {
                             cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
                             let mut is_silence = false;
                             let mut cwd = None;
+                            let mut stdin = None;
+                            let mut capture_status = false;
                             let n = args.len();
                             for (i, arg) in args.iter().skip(1).enumerate() {
                                 match arg {
@@ -270,6 +855,23 @@ impl Exp {
                                                 return Err(anyhow!("silence expects a boolean"));
                                             }
                                         }
+                                        if let Some(v) = get_field(fs, "capture_status") {
+                                            if let IDLValue::Bool(b) = v {
+                                                capture_status = *b;
+                                            } else {
+                                                return Err(anyhow!(
+                                                    "capture_status expects a boolean"
+                                                ));
+                                            }
+                                        }
+                                        if let Some(v) = get_field(fs, "stdin") {
+                                            stdin = Some(match v {
+                                                IDLValue::Text(str) => str.as_bytes().to_vec(),
+                                                v => as_blob(v.clone()).map_err(|_| {
+                                                    anyhow!("stdin expects a text or blob")
+                                                })?,
+                                            });
+                                        }
                                     }
                                     _ => return Err(anyhow!("exec expects string arguments")),
                                 }
@@ -277,9 +879,14 @@ impl Exp {
                             if let Some(cwd) = cwd {
                                 cmd.current_dir(cwd);
                             }
+                            if stdin.is_some() {
+                                cmd.stdin(Stdio::piped());
+                            }
+                            if is_silence {
+                                cmd.stderr(Stdio::null());
+                            }
                             let mut child = cmd.spawn()?;
                             let stdout = child.stdout.take().unwrap();
-                            let stderr = child.stderr.take().unwrap();
                             let final_stdout = Arc::new(Mutex::new(String::new()));
                             let final_stdout_clone = Arc::clone(&final_stdout);
 
@@ -291,33 +898,67 @@ impl Exp {
                                             println!("{line}");
                                         }
                                         let mut final_stdout = final_stdout_clone.lock().unwrap();
-                                        *final_stdout = line;
+                                        if !final_stdout.is_empty() {
+                                            final_stdout.push('\n');
+                                        }
+                                        final_stdout.push_str(&line);
                                     }
                                 });
                             });
-                            let mut stderr_thread = None;
-                            if !is_silence {
-                                stderr_thread = Some(std::thread::spawn(move || {
+                            let stderr_thread = child.stderr.take().map(|stderr| {
+                                std::thread::spawn(move || {
                                     let reader = BufReader::new(stderr);
                                     reader.lines().for_each(|line| {
                                         if let Ok(line) = line {
                                             eprintln!("{line}");
                                         }
                                     });
-                                }));
-                            }
-                            let status = child.wait()?;
+                                })
+                            });
+                            let written = match stdin {
+                                Some(input) => {
+                                    use std::io::{ErrorKind, Write};
+                                    // Output is drained by the threads above, and stderr is
+                                    // discarded when silenced, so writing cannot deadlock.
+                                    // Dropping the handle closes stdin to signal EOF.
+                                    let mut child_stdin = child.stdin.take().unwrap();
+                                    match child_stdin.write_all(&input) {
+                                        // the command may exit without reading all of its input
+                                        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+                                        res => res,
+                                    }
+                                }
+                                None => Ok(()),
+                            };
+                            // always reap the child and collect its output, even if writing failed
+                            let status = child.wait();
                             stdout_thread.join().unwrap();
                             if let Some(thread) = stderr_thread {
                                 thread.join().unwrap();
                             }
+                            written?;
+                            let status = status?;
+                            let stdout = final_stdout.lock().unwrap();
+                            if capture_status {
+                                let mut fs = vec![
+                                    IDLField {
+                                        id: Label::Named("status".to_string()),
+                                        val: IDLValue::Int(status.code().unwrap_or(-1).into()),
+                                    },
+                                    IDLField {
+                                        id: Label::Named("stdout".to_string()),
+                                        val: IDLValue::Text(stdout.clone()),
+                                    },
+                                ];
+                                fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+                                return Ok(IDLValue::Record(fs));
+                            }
                             if !status.success() {
                                 return Err(anyhow!(
                                     "exec failed with status {}",
                                     status.code().unwrap_or(-1)
                                 ));
                             }
-                            let stdout = final_stdout.lock().unwrap();
                             candid_parser::parse_idl_value(&stdout)
                                 .unwrap_or(IDLValue::Text(stdout.clone()))
                         }
@@ -325,16 +966,9 @@ impl Exp {
                     },
                     "send" if helper.offline.is_none() => match args.as_slice() {
                         [IDLValue::Blob(blob)] => {
-                            use crate::offline::{send, send_messages};
-                            let json = std::str::from_utf8(blob)?;
-                            let res = match json.trim_start().chars().next() {
-                                Some('{') => send(helper, &serde_json::from_str(json)?)?,
-                                Some('[') => send_messages(helper, &serde_json::from_str(json)?)?,
-                                _ => return Err(anyhow!("not a valid json message")),
-                            };
-                            args_to_value(res)
+                            args_to_value(crate::offline::send_blob(helper, blob)?)
                         }
-                        _ => return Err(anyhow!("send expects a json blob")),
+                        _ => return Err(anyhow!("send expects a json or cbor blob")),
                     },
                     "wasm_profiling" => match args.as_slice() {
                         [IDLValue::Text(file)] | [IDLValue::Text(file), IDLValue::Record(_)] => {
@@ -384,6 +1018,22 @@ impl Exp {
                                     } else {
                                         vec![]
                                     };
+                                    // Expand glob patterns against the function names of the module
+                                    let trace_only_funcs = trace_only_funcs
+                                        .into_iter()
+                                        .flat_map(|pattern| {
+                                            if !pattern.contains(['*', '?']) {
+                                                return vec![pattern];
+                                            }
+                                            m.funcs
+                                                .iter()
+                                                .filter_map(|f| f.name.clone())
+                                                .filter(|name| {
+                                                    crate::utils::glob_match(&pattern, name)
+                                                })
+                                                .collect()
+                                        })
+                                        .collect();
                                     Config {
                                         trace_only_funcs,
                                         start_address: start_page
@@ -432,21 +1082,64 @@ impl Exp {
                         }
                         _ => {
                             return Err(anyhow!(
-                                "flamegraph expects (canister id, title name, svg file name)"
+                                "flamegraph expects (canister id, title name, svg or folded file name)"
                             ))
                         }
                     },
-                    "output" => match args.as_slice() {
+                    "get_trace" => match args.as_slice() {
+                        [IDLValue::Principal(cid)] => {
+                            let mut map = helper.canister_map.borrow_mut();
+                            let names = match map.get(&helper.agent, cid) {
+                                Ok(crate::helper::CanisterInfo {
+                                    profiling: Some(names),
+                                    ..
+                                }) => names,
+                                _ => return Err(anyhow!("{} is not instrumented", cid)),
+                            };
+                            crate::profiling::get_trace(&helper.agent, cid, names)?
+                        }
+                        _ => return Err(anyhow!("get_trace expects a canister id")),
+                    },
+                    "flamegraph_diff" => match args.as_slice() {
+                        [
+                            IDLValue::Text(title),
+                            IDLValue::Text(before),
+                            IDLValue::Text(after),
+                            IDLValue::Text(file),
+                        ] => {
+                            let dir = std::env::current_dir()?;
+                            let mut path = resolve_path(&dir, file);
+                            if path.extension().is_none() {
+                                path.set_extension("svg");
+                            }
+                            crate::profiling::render_diff(
+                                title,
+                                resolve_path(&dir, before),
+                                resolve_path(&dir, after),
+                                path,
+                            )?;
+                            IDLValue::Null
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "flamegraph_diff expects (title, before file, after file, svg file name)"
+                            ))
+                        }
+                    },
+                    "output" | "write" => match args.as_slice() {
                         [IDLValue::Text(file), IDLValue::Text(content)] => {
                             use std::fs::OpenOptions;
                             use std::io::Write;
                             let path = resolve_path(&std::env::current_dir()?, file);
-                            let mut file =
-                                OpenOptions::new().append(true).create(true).open(path)?;
+                            let mut file = if func == "output" {
+                                OpenOptions::new().append(true).create(true).open(path)?
+                            } else {
+                                std::fs::File::create(path)?
+                            };
                             file.write_all(content.as_bytes())?;
                             IDLValue::Text(content.to_string())
                         }
-                        _ => return Err(anyhow!("wasm_profiling expects (file path, content)")),
+                        _ => return Err(anyhow!("{func} expects (file path, content)")),
                     },
                     "stringify" => {
                         use std::fmt::Write;
@@ -456,30 +1149,229 @@ impl Exp {
                         }
                         IDLValue::Text(res)
                     }
-                    "concat" => match args.as_slice() {
-                        [IDLValue::Vec(s1), IDLValue::Vec(s2)] => {
-                            let mut res = Vec::from(s1.as_slice());
-                            res.extend_from_slice(s2);
-                            IDLValue::Vec(res)
-                        }
-                        [IDLValue::Blob(b1), IDLValue::Blob(b2)] => {
-                            let mut res = Vec::from(b1.as_slice());
-                            res.extend_from_slice(b2);
-                            IDLValue::Blob(res)
-                        }
-                        [IDLValue::Text(s1), IDLValue::Text(s2)] => {
-                            IDLValue::Text(String::from(s1) + s2)
-                        }
-                        [IDLValue::Record(f1), IDLValue::Record(f2)] => {
-                            let mut fs = Vec::from(f1.as_slice());
-                            fs.extend_from_slice(f2);
+                    "cost_diff" => match args.as_slice() {
+                        [IDLValue::Record(before), IDLValue::Record(after)] => {
+                            crate::utils::cost_diff(before, after)?
+                        }
+                        _ => return Err(anyhow!("cost_diff expects two cost records")),
+                    },
+                    "perf_record" => match args.as_slice() {
+                        [IDLValue::Text(tag), IDLValue::Record(metrics)] => {
+                            crate::utils::perf_record(tag, metrics.clone())?;
+                            IDLValue::Null
+                        }
+                        _ => return Err(anyhow!("perf_record expects (tag, metrics record)")),
+                    },
+                    "perf_compare" => match args.as_slice() {
+                        [IDLValue::Text(tag_a), IDLValue::Text(tag_b)] => {
+                            crate::utils::perf_compare(tag_a, tag_b)?
+                        }
+                        _ => return Err(anyhow!("perf_compare expects (tag_a, tag_b)")),
+                    },
+                    "exit" => match args.as_slice() {
+                        [code] => {
+                            let IDLValue::Int32(code) =
+                                cast_type(code.clone(), &TypeInner::Int32.into())
+                                    .map_err(|_| anyhow!("exit expects an int32 exit code"))?
+                            else {
+                                unreachable!()
+                            };
+                            if helper.offline.is_some() && !helper.messages.borrow().is_empty() {
+                                helper.dump_ingress()?;
+                            }
+                            std::process::exit(code);
+                        }
+                        _ => return Err(anyhow!("exit expects an exit code")),
+                    },
+                    "report" => match args.as_slice() {
+                        [] => {
+                            let mut stats = helper.test_stats.borrow_mut();
+                            println!(
+                                "Test summary: {} passed, {} failed",
+                                stats.passed,
+                                stats.failed.len()
+                            );
+                            for msg in stats.failed.iter() {
+                                println!("FAILED: {msg}");
+                            }
+                            if !stats.failed.is_empty() {
+                                stats.exit_code = Some(1);
+                            }
+                            let mut fs = vec![
+                                IDLField {
+                                    id: Label::Named("passed".to_string()),
+                                    val: IDLValue::Nat64(stats.passed as u64),
+                                },
+                                IDLField {
+                                    id: Label::Named("failed".to_string()),
+                                    val: IDLValue::Nat64(stats.failed.len() as u64),
+                                },
+                            ];
+                            fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+                            IDLValue::Record(fs)
+                        }
+                        _ => return Err(anyhow!("report expects no arguments")),
+                    },
+                    "prometheus" => match args.as_slice() {
+                        [IDLValue::Record(metrics)] => {
+                            IDLValue::Text(crate::utils::to_prometheus(metrics, &[])?)
+                        }
+                        [IDLValue::Record(metrics), IDLValue::Record(labels)] => {
+                            IDLValue::Text(crate::utils::to_prometheus(metrics, labels)?)
+                        }
+                        _ => {
+                            return Err(anyhow!(
+                                "prometheus expects a record of metrics and optionally a record of labels"
+                            ))
+                        }
+                    },
+                    "concat" => {
+                        let mut iter = args.into_iter();
+                        let Some(first) = iter.next() else {
+                            return Err(anyhow!("concat expects at least one argument"));
+                        };
+                        iter.try_fold(first, concat)?
+                    }
+                    "resolve_names" => match args.as_slice() {
+                        [IDLValue::Vec(ids)] => {
+                            let mut fs = Vec::with_capacity(ids.len());
+                            for id in ids {
+                                let IDLValue::Principal(id) = id else {
+                                    return Err(anyhow!(
+                                        "resolve_names expects a vec of principals"
+                                    ));
+                                };
+                                let id_text = id.to_text();
+                                let name = helper.env.find_principal_name(id).unwrap_or(&id_text);
+                                fs.push(IDLField {
+                                    id: Label::Named(id_text.clone()),
+                                    val: IDLValue::Text(name.to_string()),
+                                });
+                            }
+                            fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+                            check_unique(fs.iter().map(|f| &f.id)).map_err(|e| {
+                                anyhow!("resolve_names expects distinct principals: {e}")
+                            })?;
+                            IDLValue::Record(fs)
+                        }
+                        _ => return Err(anyhow!("resolve_names expects a vec of principals")),
+                    },
+                    "is_empty" => match args.as_slice() {
+                        [IDLValue::Text(s)] => IDLValue::Bool(s.is_empty()),
+                        [IDLValue::Vec(vs)] => IDLValue::Bool(vs.is_empty()),
+                        [IDLValue::Blob(b)] => IDLValue::Bool(b.is_empty()),
+                        [IDLValue::Record(fs)] => IDLValue::Bool(fs.is_empty()),
+                        _ => return Err(anyhow!("is_empty expects a text, vec, blob or record")),
+                    },
+                    "has_field" => match args.as_slice() {
+                        [IDLValue::Record(fs), IDLValue::Text(name)] => {
+                            IDLValue::Bool(get_field(fs, name).is_some())
+                        }
+                        _ => return Err(anyhow!("has_field expects (record, field name)")),
+                    },
+                    "remove_field" => match args.as_slice() {
+                        [IDLValue::Record(fs), IDLValue::Text(name)] => {
+                            let label = Label::Named(name.to_string());
+                            let mut fs: Vec<_> =
+                                fs.iter().filter(|f| f.id != label).cloned().collect();
                             fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
                             check_unique(fs.iter().map(|f| &f.id))?;
                             IDLValue::Record(fs)
                         }
-                        _ => return Err(anyhow!("concat expects two vec, record or text")),
+                        _ => return Err(anyhow!("remove_field expects (record, field name)")),
+                    },
+                    "field_hash" => match args.as_slice() {
+                        [IDLValue::Text(name)] => IDLValue::Nat32(candid::idl_hash(name)),
+                        _ => return Err(anyhow!("field_hash expects text")),
+                    },
+                    "pick" | "omit" => match args.as_slice() {
+                        [IDLValue::Record(fs), IDLValue::Vec(names)] => {
+                            let mut labels = Vec::with_capacity(names.len());
+                            for name in names {
+                                let IDLValue::Text(name) = name else {
+                                    return Err(anyhow!("{func} expects a vec of field names"));
+                                };
+                                if func == "pick" && get_field(fs, name).is_none() {
+                                    return Err(anyhow!("field {name} not found"));
+                                }
+                                labels.push(Label::Named(name.to_string()));
+                            }
+                            let keep = func == "pick";
+                            let mut fs: Vec<_> = fs
+                                .iter()
+                                .filter(|f| labels.contains(&f.id) == keep)
+                                .cloned()
+                                .collect();
+                            fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+                            check_unique(fs.iter().map(|f| &f.id))?;
+                            IDLValue::Record(fs)
+                        }
+                        _ => return Err(anyhow!("{func} expects (record, vec of field names)")),
+                    },
+                    "to_le_bytes" | "to_be_bytes" => match args.as_slice() {
+                        [n, width] => {
+                            let width = as_u32(width)
+                                .with_context(|| anyhow!("{func} expects a width of 8/16/32/64"))?;
+                            let blob = crate::utils::int_to_bytes(n, width, func == "to_le_bytes")?;
+                            IDLValue::Blob(blob)
+                        }
+                        _ => return Err(anyhow!("{func} expects (number, width)")),
+                    },
+                    "from_le_bytes" | "from_be_bytes" => match args.as_slice() {
+                        [blob] => {
+                            let blob = as_blob(blob.clone())?;
+                            crate::utils::bytes_to_int(&blob, func == "from_le_bytes")?
+                        }
+                        _ => return Err(anyhow!("{func} expects a blob")),
+                    },
+                    "leb128_encode" => match args.as_slice() {
+                        [n] => IDLValue::Blob(crate::utils::leb128_encode(n, false)?),
+                        [n, IDLValue::Bool(signed)] => {
+                            IDLValue::Blob(crate::utils::leb128_encode(n, *signed)?)
+                        }
+                        _ => return Err(anyhow!("leb128_encode expects (number, signed?)")),
+                    },
+                    "leb128_decode" => match args.as_slice() {
+                        [blob] | [blob, IDLValue::Bool(_)] => {
+                            let signed = matches!(args.get(1), Some(IDLValue::Bool(true)));
+                            crate::utils::leb128_decode(&as_blob(blob.clone())?, signed)?
+                        }
+                        _ => return Err(anyhow!("leb128_decode expects (blob, signed?)")),
+                    },
+                    "typeof" => match args.as_slice() {
+                        [v] => IDLValue::Text(type_of(v)),
+                        _ => return Err(anyhow!("typeof expects one argument")),
+                    },
+                    "tag" => match args.as_slice() {
+                        [IDLValue::Variant(VariantValue(f, _))] => IDLValue::Text(f.id.to_string()),
+                        _ => return Err(anyhow!("tag expects a variant value")),
+                    },
+                    "payload" => match args.as_slice() {
+                        [IDLValue::Variant(VariantValue(f, _))] => f.val.clone(),
+                        _ => return Err(anyhow!("payload expects a variant value")),
+                    },
+                    "is_some" | "is_none" => match args.as_slice() {
+                        [IDLValue::Opt(_)] => IDLValue::Bool(func == "is_some"),
+                        [IDLValue::None | IDLValue::Null] => IDLValue::Bool(func == "is_none"),
+                        _ => return Err(anyhow!("{func} expects an opt value")),
+                    },
+                    "unwrap_or" => match args.as_slice() {
+                        [IDLValue::Opt(v), _] => *v.clone(),
+                        [IDLValue::None | IDLValue::Null, default] => default.clone(),
+                        _ => return Err(anyhow!("unwrap_or expects (opt value, default)")),
                     },
                     "eq" | "neq" => match args.as_slice() {
+                        [v1, v2] if is_number(v1) && is_number(v2) => {
+                            let ty = if matches!(v1, IDLValue::Float32(_) | IDLValue::Float64(_))
+                                || matches!(v2, IDLValue::Float32(_) | IDLValue::Float64(_))
+                            {
+                                TypeInner::Float64.into()
+                            } else {
+                                TypeInner::Int.into()
+                            };
+                            let eq = cast_type(v1.clone(), &ty)? == cast_type(v2.clone(), &ty)?;
+                            IDLValue::Bool(if func == "eq" { eq } else { !eq })
+                        }
                         [v1, v2] => {
                             if v1.value_ty() != v2.value_ty() {
                                 return Err(anyhow!(
@@ -509,9 +1401,54 @@ impl Exp {
                         [IDLValue::Bool(v)] => IDLValue::Bool(!v),
                         _ => return Err(anyhow!("not expects a bool value")),
                     },
+                    "ema" => match args.as_slice() {
+                        [IDLValue::Vec(series), alpha] => {
+                            let as_f64 = |v: &IDLValue| -> Result<f64> {
+                                let IDLValue::Float64(f) =
+                                    cast_type(v.clone(), &TypeInner::Float64.into())?
+                                else {
+                                    unreachable!()
+                                };
+                                Ok(f)
+                            };
+                            let alpha = as_f64(alpha)?;
+                            if !(0.0..=1.0).contains(&alpha) {
+                                return Err(anyhow!("ema expects alpha to be between 0 and 1"));
+                            }
+                            let mut iter = series.iter();
+                            let Some(first) = iter.next() else {
+                                return Err(anyhow!("ema expects a non-empty series"));
+                            };
+                            let mut avg = as_f64(first)?;
+                            for v in iter {
+                                avg = alpha * as_f64(v)? + (1.0 - alpha) * avg;
+                            }
+                            IDLValue::Float64(avg)
+                        }
+                        _ => return Err(anyhow!("ema expects (vec of numbers, alpha)")),
+                    },
                     "lt" | "lte" | "gt" | "gte" | "add" | "sub" | "mul" | "div" => match args
                         .as_slice()
                     {
+                        [IDLValue::Text(_), IDLValue::Text(_)]
+                        | [IDLValue::Principal(_), IDLValue::Principal(_)]
+                            if matches!(func.as_str(), "lt" | "lte" | "gt" | "gte") =>
+                        {
+                            let ord = match (&args[0], &args[1]) {
+                                (IDLValue::Text(s1), IDLValue::Text(s2)) => s1.cmp(s2),
+                                (IDLValue::Principal(p1), IDLValue::Principal(p2)) => {
+                                    p1.as_slice().cmp(p2.as_slice())
+                                }
+                                _ => unreachable!(),
+                            };
+                            IDLValue::Bool(match func.as_str() {
+                                "lt" => ord.is_lt(),
+                                "lte" => ord.is_le(),
+                                "gt" => ord.is_gt(),
+                                "gte" => ord.is_ge(),
+                                _ => unreachable!(),
+                            })
+                        }
                         [IDLValue::Float32(_) | IDLValue::Float64(_), _]
                         | [_, IDLValue::Float32(_) | IDLValue::Float64(_)] => {
                             let IDLValue::Float64(v1) =
@@ -549,6 +1486,9 @@ impl Exp {
                                 "add" => IDLValue::Number((v1 + v2).to_string()),
                                 "sub" => IDLValue::Number((v1 - v2).to_string()),
                                 "mul" => IDLValue::Number((v1 * v2).to_string()),
+                                "div" if v2 == 0.into() => {
+                                    return Err(anyhow!("division by zero"))
+                                }
                                 "div" => IDLValue::Number((v1 / v2).to_string()),
                                 "lt" => IDLValue::Bool(v1 < v2),
                                 "lte" => IDLValue::Bool(v1 <= v2),
@@ -563,24 +1503,21 @@ impl Exp {
                 }
             }
             Exp::Decode { method, blob } => {
-                let blob = blob.eval(helper)?;
-                if *blob.value_ty() != TypeInner::Vec(TypeInner::Nat8.into()) {
-                    return Err(anyhow!("not a blob"));
-                }
-                let bytes: Vec<u8> = match blob {
-                    IDLValue::Blob(b) => b,
-                    IDLValue::Vec(vs) => vs
-                        .into_iter()
-                        .map(|v| match v {
-                            IDLValue::Nat8(u) => u,
-                            _ => unreachable!(),
-                        })
-                        .collect(),
-                    _ => unreachable!(),
+                let bytes = match blob.eval(helper)? {
+                    IDLValue::Text(s) => {
+                        use base64::{engine::general_purpose::STANDARD, Engine};
+                        let s = s.trim();
+                        hex::decode(s.trim_start_matches("0x"))
+                            .or_else(|_| STANDARD.decode(s))
+                            .map_err(|_| {
+                                anyhow!("decode expects a blob, or text in hex or base64")
+                            })?
+                    }
+                    v => as_blob(v)?,
                 };
                 let args = match method {
                     Some(method) => {
-                        let info = method.get_info(helper, false)?;
+                        let info = method.resolve(helper)?.get_info(helper, false)?;
                         if let Some((env, func)) = info.signature {
                             IDLArgs::from_bytes_with_types(&bytes, &env, &func.rets)?
                         } else {
@@ -591,9 +1528,10 @@ impl Exp {
                 };
                 args_to_value(args)
             }
-            Exp::ParCall { calls } => {
+            Exp::ParCall { calls, keep_going } => {
                 let mut futures = Vec::with_capacity(calls.len());
-                for call in calls {
+                for mut call in calls {
+                    call.method = call.method.resolve(helper)?;
                     let mut args = Vec::with_capacity(call.args.len());
                     for arg in call.args.into_iter() {
                         args.push(arg.eval(helper)?);
@@ -622,13 +1560,30 @@ impl Exp {
                     };
                     futures.push(call_future);
                 }
-                let res = parallel_calls(futures)?;
-                let res = IDLArgs {
-                    args: res.into_iter().map(args_to_value).collect(),
+                let args = if keep_going {
+                    runtime()
+                        .block_on(futures::future::join_all(futures))
+                        .into_iter()
+                        .map(|res| {
+                            let (tag, val) = match res {
+                                Ok(res) => ("Ok", args_to_value(res)),
+                                Err(e) => ("Err", IDLValue::Text(format!("{e}"))),
+                            };
+                            let f = IDLField {
+                                id: Label::Named(tag.to_string()),
+                                val,
+                            };
+                            IDLValue::Variant(VariantValue(Box::new(f), 0))
+                        })
+                        .collect()
+                } else {
+                    let res = parallel_calls(futures)?;
+                    res.into_iter().map(args_to_value).collect()
                 };
-                args_to_value(res)
+                args_to_value(IDLArgs { args })
             }
             Exp::Call { method, args, mode } => {
+                let method = method.map(|m| m.resolve(helper)).transpose()?;
                 let args = if let Some(args) = args {
                     let mut res = Vec::with_capacity(args.len());
                     for arg in args.into_iter() {
@@ -639,7 +1594,7 @@ impl Exp {
                     None
                 };
                 let opt_info = if let Some(method) = &method {
-                    let is_encode = matches!(mode, CallMode::Encode);
+                    let is_encode = matches!(mode, CallMode::Encode | CallMode::EncodeHex);
                     Some(method.get_info(helper, is_encode)?)
                 } else {
                     None
@@ -679,6 +1634,7 @@ impl Exp {
                 };
                 match mode {
                     CallMode::Encode => IDLValue::Blob(bytes),
+                    CallMode::EncodeHex => IDLValue::Text(hex::encode(bytes)),
                     CallMode::Call => {
                         use crate::profiling::{get_cycles, ok_to_profile};
                         let method = method.unwrap(); // okay to unwrap from parser
@@ -689,12 +1645,24 @@ impl Exp {
                         } else {
                             0
                         };
+                        // A query does not persist the instruction counter, so it is profiled as an update call.
+                        let mut signature = info.signature.clone();
+                        if let (true, Some((_, func))) = (ok_to_profile, &mut signature) {
+                            if func.is_query() {
+                                eprintln!(
+                                    "Warning: calling query {} as an update call for profiling. \
+                                     Its state changes are committed.",
+                                    method.method
+                                );
+                                func.modes.clear();
+                            }
+                        }
                         let res = call(
                             helper,
                             &info.canister_id,
                             &method.method,
                             &bytes,
-                            &info.signature,
+                            &signature,
                             &helper.offline,
                         )?;
                         if ok_to_profile {
@@ -710,35 +1678,56 @@ impl Exp {
                             args_to_value(res)
                         }
                     }
-                    CallMode::Proxy(id) => {
+                    CallMode::Async => {
+                        let method = method.unwrap();
+                        let info = opt_info.unwrap();
+                        if info.signature.as_ref().is_some_and(|(_, f)| f.is_query()) {
+                            return Err(anyhow!("call async expects an update method"));
+                        }
+                        let request_id =
+                            call_async(helper, &info.canister_id, &method.method, &bytes)?;
+                        IDLValue::Blob(request_id)
+                    }
+                    CallMode::Proxy(id, cycles) => {
                         let method = method.unwrap();
                         let canister_id = str_to_principal(&method.canister, helper)?;
                         let proxy_id = str_to_principal(&id, helper)?;
+                        let cycles = match cycles {
+                            Some(cycles) => {
+                                match cast_type(cycles.eval(helper)?, &TypeInner::Nat64.into()) {
+                                    Ok(IDLValue::Nat64(cycles)) => cycles,
+                                    _ => return Err(anyhow!("cycles expects a nat64")),
+                                }
+                            }
+                            None => 0,
+                        };
                         let mut env = MyHelper::new(
                             helper.agent.clone(),
                             helper.agent_url.clone(),
                             helper.offline.clone(),
                             helper.verbose,
                         );
-                        env.canister_map.borrow_mut().0.insert(
+                        env.canister_map.borrow_mut().map.insert(
                             proxy_id,
                             helper
                                 .canister_map
                                 .borrow()
-                                .0
+                                .map
                                 .get(&proxy_id)
                                 .ok_or_else(|| {
                                     anyhow!("{} canister interface not found", proxy_id)
                                 })?
                                 .clone(),
                         );
+                        env.retry = helper.retry.clone();
+                        env.timeout = helper.timeout;
                         env.env.0.insert("_msg".to_string(), IDLValue::Blob(bytes));
                         let code = format!(
                             r#"
 let _ = call "{id}".wallet_call(
   record {{
     args = _msg;
-    cycles = 0;
+    cycles = {cycles};
     method_name = "{method}";
     canister = principal "{canister}";
   }}
@@ -747,7 +1736,8 @@ let _ = decode as "{canister}".{method} _.Ok.return;
 "#,
                             id = proxy_id,
                             canister = canister_id,
-                            method = method.method
+                            method = method.method,
+                            cycles = cycles,
                         );
                         let cmds = pretty_parse::<crate::command::Commands>("forward_call", &code)?;
                         for (cmd, _) in cmds.0.into_iter() {
@@ -757,6 +1747,29 @@ let _ = decode as "{canister}".{method} _.Ok.return;
                     }
                 }
             }
+            Exp::Match(e, arms) => {
+                let v = e.eval(helper)?;
+                let IDLValue::Variant(VariantValue(f, _)) = &v else {
+                    return Err(anyhow!("match expects a variant value"));
+                };
+                let arm = arms
+                    .into_iter()
+                    .find(|arm| arm.label.as_ref().is_none_or(|l| *l == f.id))
+                    .ok_or_else(|| anyhow!("no match arm for variant tag {}", f.id))?;
+                match arm.var {
+                    None => arm.body.eval(helper)?,
+                    Some(var) => {
+                        let payload = if arm.label.is_some() {
+                            f.val.clone()
+                        } else {
+                            v.clone()
+                        };
+                        let mut helper = helper.spawn();
+                        helper.env.0.insert(var, payload);
+                        arm.body.eval(&helper)?
+                    }
+                }
+            }
             Exp::Bool(b) => IDLValue::Bool(b),
             Exp::Null => IDLValue::Null,
             Exp::Text(s) => IDLValue::Text(s),
@@ -798,7 +1811,7 @@ let _ = decode as "{canister}".{method} _.Ok.return;
 impl std::str::FromStr for Exp {
     type Err = ParserError;
     fn from_str(str: &str) -> Result<Self, Self::Err> {
-        let lexer = Tokenizer::new(str);
+        let lexer = Tokenizer::new_exp(str);
         super::grammar::ExpParser::new().parse(lexer)
     }
 }
@@ -810,6 +1823,22 @@ pub struct MethodInfo {
     pub profiling: Option<BTreeMap<u16, String>>,
 }
 impl Method {
+    pub fn resolve(mut self, helper: &MyHelper) -> Result<Self> {
+        if let Some(e) = self.canister_exp.take() {
+            self.canister = match e.eval(helper)? {
+                IDLValue::Principal(id) | IDLValue::Service(id) => id.to_text(),
+                IDLValue::Text(id) => str_to_principal(&id, helper)?.to_text(),
+                _ => return Err(anyhow!("canister needs to be a principal")),
+            };
+        }
+        if let Some(e) = self.method_exp.take() {
+            let IDLValue::Text(method) = e.eval(helper)? else {
+                return Err(anyhow!("method name needs to be a text value"));
+            };
+            self.method = method;
+        }
+        Ok(self)
+    }
     pub fn get_info(&self, helper: &MyHelper, is_encode: bool) -> Result<MethodInfo> {
         if is_encode && self.method == "__init_args" {
             if let Some(IDLValue::Blob(bytes)) = helper.env.0.get(&self.canister) {
@@ -902,6 +1931,47 @@ impl Method {
     }
 }
 
+fn is_number(v: &IDLValue) -> bool {
+    use IDLValue::*;
+    matches!(
+        v,
+        Number(_)
+            | Int(_)
+            | Nat(_)
+            | Int8(_)
+            | Int16(_)
+            | Int32(_)
+            | Int64(_)
+            | Nat8(_)
+            | Nat16(_)
+            | Nat32(_)
+            | Nat64(_)
+            | Float32(_)
+            | Float64(_)
+    )
+}
+
+fn concat(v1: IDLValue, v2: IDLValue) -> Result<IDLValue> {
+    Ok(match (v1, v2) {
+        (IDLValue::Vec(mut s1), IDLValue::Vec(s2)) => {
+            s1.extend(s2);
+            IDLValue::Vec(s1)
+        }
+        (IDLValue::Blob(mut b1), IDLValue::Blob(b2)) => {
+            b1.extend(b2);
+            IDLValue::Blob(b1)
+        }
+        (IDLValue::Text(s1), IDLValue::Text(s2)) => IDLValue::Text(s1 + &s2),
+        (IDLValue::Record(mut fs), IDLValue::Record(f2)) => {
+            fs.extend(f2);
+            fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+            check_unique(fs.iter().map(|f| &f.id))?;
+            IDLValue::Record(fs)
+        }
+        _ => return Err(anyhow!("concat expects values of the same kind")),
+    })
+}
+
 pub fn apply_func(helper: &MyHelper, func: &str, args: Vec<IDLValue>) -> Result<IDLValue> {
     match helper.func_env.0.get(func) {
         None => Err(anyhow!("Unknown function {}", func)),
@@ -926,15 +1996,70 @@ pub fn apply_func(helper: &MyHelper, func: &str, args: Vec<IDLValue>) -> Result<
         }
     }
 }
-#[tokio::main(flavor = "multi_thread", worker_threads = 10)]
-async fn parallel_calls(
+/// Call a method with a single argument, using the method type from the canister metadata when available.
+fn call_method(helper: &MyHelper, canister: &str, method: &str, arg: IDLValue) -> Result<IDLValue> {
+    let method = Method {
+        canister: canister.to_string(),
+        method: method.to_string(),
+        ..Default::default()
+    };
+    let info = method.get_info(helper, false)?;
+    let args = IDLArgs::new(&[arg]);
+    let bytes = match &info.signature {
+        Some((env, func)) => args.to_bytes_with_types(env, &func.args)?,
+        None => args.to_bytes()?,
+    };
+    let res = call(
+        helper,
+        &info.canister_id,
+        &method.method,
+        &bytes,
+        &info.signature,
+        &helper.offline,
+    )?;
+    Ok(args_to_value(res))
+}
+fn parallel_calls(
     futures: Vec<impl std::future::Future<Output = anyhow::Result<IDLArgs>>>,
 ) -> anyhow::Result<Vec<IDLArgs>> {
-    let res = try_join_all(futures).await?;
+    let res = runtime().block_on(try_join_all(futures))?;
     Ok(res)
 }
-#[tokio::main]
-async fn call(
+/// Submit an update call without waiting for the reply, and return the request id.
+fn call_async(
+    helper: &MyHelper,
+    canister_id: &Principal,
+    method: &str,
+    args: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    if helper.offline.is_some() {
+        return Err(anyhow!("call async is not supported in offline mode"));
+    }
+    runtime().block_on(async move {
+        let agent = &helper.agent;
+        let effective_id = get_effective_canister_id(*canister_id, method, args)?
+            .unwrap_or(helper.default_effective_canister_id);
+        let signed = agent
+            .update(canister_id, method)
+            .with_arg(args)
+            .with_effective_canister_id(effective_id)
+            .sign()?;
+        // Resubmitting the same signed message is idempotent, so it is safe to retry.
+        with_timeout(
+            helper.timeout,
+            method,
+            with_retry(&helper.retry, || async {
+                agent
+                    .update_signed(effective_id, signed.signed_update.clone())
+                    .await
+                    .map(|_| Vec::new())
+            }),
+        )
+        .await?;
+        Ok(signed.request_id.as_slice().to_vec())
+    })
+}
+fn call(
     helper: &MyHelper,
     canister_id: &Principal,
     method: &str,
@@ -942,68 +2067,172 @@ async fn call(
     opt_func: &Option<(TypeEnv, Function)>,
     offline: &Option<OfflineOutput>,
 ) -> anyhow::Result<IDLArgs> {
-    use crate::offline::*;
-    let agent = &helper.agent;
-    let effective_id = get_effective_canister_id(*canister_id, method, args)?
-        .unwrap_or(helper.default_effective_canister_id);
-    let is_query = opt_func
-        .as_ref()
-        .map(|(_, f)| f.is_query())
-        .unwrap_or(false);
-    let bytes = if is_query {
-        let mut builder = agent.query(canister_id, method);
-        builder = builder
-            .with_arg(args)
-            .with_effective_canister_id(effective_id);
-        if let Some(offline) = offline {
-            let mut msgs = helper.messages.borrow_mut();
-            let signed = builder.sign()?;
-            let message = IngressWithStatus {
-                ingress: Ingress {
-                    call_type: "query".to_owned(),
-                    request_id: None,
-                    content: hex::encode(signed.signed_query),
-                },
-                request_status: None,
+    runtime().block_on(async move {
+        use crate::offline::*;
+        let agent = &helper.agent;
+        let effective_id = get_effective_canister_id(*canister_id, method, args)?
+            .unwrap_or(helper.default_effective_canister_id);
+        let is_query = opt_func
+            .as_ref()
+            .map(|(_, f)| f.is_query())
+            .unwrap_or(false);
+        let bytes = if is_query {
+            let builder = || {
+                agent
+                    .query(canister_id, method)
+                    .with_arg(args)
+                    .with_effective_canister_id(effective_id)
             };
-            msgs.push(message.clone());
-            output_message(serde_json::to_string(&message)?, offline)?;
-            return Ok(IDLArgs::new(&[]));
+            if let Some(offline) = offline {
+                let mut msgs = helper.messages.borrow_mut();
+                let signed = builder().sign()?;
+                let message = IngressWithStatus {
+                    ingress: Ingress {
+                        call_type: "query".to_owned(),
+                        request_id: None,
+                        content: hex::encode(signed.signed_query),
+                        effective_id: None,
+                    },
+                    request_status: None,
+                };
+                msgs.push(message.clone());
+                output_message(&message, offline)?;
+                return Ok(IDLArgs::new(&[]));
+            } else {
+                with_timeout(
+                    helper.timeout,
+                    method,
+                    with_retry(&helper.retry, || builder().call()),
+                )
+                .await?
+            }
         } else {
-            builder.call().await?
-        }
-    } else {
-        let mut builder = agent.update(canister_id, method);
-        builder = builder
-            .with_arg(args)
-            .with_effective_canister_id(effective_id);
-        if let Some(offline) = offline {
-            let mut msgs = helper.messages.borrow_mut();
-            let signed = builder.sign()?;
-            let status = agent.sign_request_status(effective_id, signed.request_id)?;
-            let message = IngressWithStatus {
-                ingress: Ingress {
-                    call_type: "update".to_owned(),
-                    request_id: Some(hex::encode(signed.request_id.as_slice())),
-                    content: hex::encode(signed.signed_update),
-                },
-                request_status: Some(RequestStatus {
-                    canister_id: status.effective_canister_id,
-                    request_id: hex::encode(status.request_id.as_slice()),
-                    content: hex::encode(status.signed_request_status),
-                }),
+            let builder = || {
+                agent
+                    .update(canister_id, method)
+                    .with_arg(args)
+                    .with_effective_canister_id(effective_id)
             };
-            msgs.push(message.clone());
-            output_message(serde_json::to_string(&message)?, offline)?;
-            return Ok(IDLArgs::new(&[]));
+            if let Some(offline) = offline {
+                let mut msgs = helper.messages.borrow_mut();
+                let signed = builder().sign()?;
+                let status = agent.sign_request_status(effective_id, signed.request_id)?;
+                let message = IngressWithStatus {
+                    ingress: Ingress {
+                        call_type: "update".to_owned(),
+                        request_id: Some(hex::encode(signed.request_id.as_slice())),
+                        content: hex::encode(signed.signed_update),
+                        effective_id: None,
+                    },
+                    request_status: Some(RequestStatus {
+                        canister_id: status.effective_canister_id,
+                        request_id: hex::encode(status.request_id.as_slice()),
+                        content: hex::encode(status.signed_request_status),
+                    }),
+                };
+                msgs.push(message.clone());
+                output_message(&message, offline)?;
+                return Ok(IDLArgs::new(&[]));
+            } else {
+                use ic_agent::agent::CallResponse;
+                // Sign once, so that a retry resubmits the same request instead of running the update twice.
+                let signed = builder().sign()?;
+                with_timeout(
+                    helper.timeout,
+                    method,
+                    with_retry(&helper.retry, || async {
+                        let signed_update = signed.signed_update.clone();
+                        match agent.update_signed(effective_id, signed_update).await {
+                            Ok(CallResponse::Response(blob)) => Ok(blob),
+                            Ok(CallResponse::Poll(request_id)) => agent
+                                .wait(&request_id, effective_id)
+                                .await
+                                .map(|(blob, _)| blob),
+                            Err(e) => Err(e),
+                        }
+                    }),
+                )
+                .await?
+            }
+        };
+        let res = if let Some((env, func)) = opt_func {
+            IDLArgs::from_bytes_with_types(&bytes, env, &func.rets)?
         } else {
-            builder.call_and_wait().await?
-        }
-    };
-    let res = if let Some((env, func)) = opt_func {
-        IDLArgs::from_bytes_with_types(&bytes, env, &func.rets)?
-    } else {
-        IDLArgs::from_bytes(&bytes)?
-    };
-    Ok(res)
+            IDLArgs::from_bytes(&bytes)?
+        };
+        Ok(res)
+    })
+}
+
+#[test]
+fn test_div_by_zero() -> Result<()> {
+    let helper = crate::helper::test_helper();
+    let eval = |s: &str| s.parse::<Exp>().unwrap().eval(&helper);
+    assert!(eval("div(1, 0)").is_err());
+    assert_eq!(eval("div(7, 2)")?, IDLValue::Number("3".to_string()));
+    assert_eq!(eval("div(1, 0.0)")?, IDLValue::Float64(f64::INFINITY));
+    Ok(())
+}
+
+#[test]
+fn test_match() -> Result<()> {
+    let helper = crate::helper::test_helper();
+    let eval = |s: &str| s.parse::<Exp>().unwrap().eval(&helper);
+    let arms = r#"{ Ok(v) => add(v, 1); Err(e) => e; _(v) => tag(v) }"#;
+    assert_eq!(
+        eval(&format!("match variant {{ Ok = 42 }} {arms}"))?,
+        IDLValue::Number("43".to_string())
+    );
+    assert_eq!(
+        eval(&format!(r#"match variant {{ Err = "no" }} {arms}"#))?,
+        IDLValue::Text("no".to_string())
+    );
+    // The fallback arm binds the whole variant value.
+    assert_eq!(
+        eval(&format!("match variant {{ Other }} {arms}"))?,
+        IDLValue::Text("Other".to_string())
+    );
+    assert_eq!(
+        eval("match variant { Other } { Ok => 1; _ => 2 }")?,
+        IDLValue::Number("2".to_string())
+    );
+    assert!(eval("match variant { Other } { Ok => 1; Err => 2 }").is_err());
+    assert!(eval("match 42 { _ => 1 }").is_err());
+    Ok(())
+}
+
+#[test]
+fn test_text_interpolation() -> Result<()> {
+    let mut helper = crate::helper::test_helper();
+    helper
+        .env
+        .0
+        .insert("amount".to_string(), IDLValue::Nat64(42));
+    let exp = r#""sent \(add(amount, 1)) to \("a(" ) \\(x)""#.parse::<Exp>().unwrap();
+    assert_eq!(
+        exp.eval(&helper)?,
+        IDLValue::Text("sent 43 to a( \\(x)".to_string())
+    );
+    assert!(r#""\(amount""#.parse::<Exp>().is_err());
+    Ok(())
+}
+
+#[test]
+fn test_opt_selectors() -> Result<()> {
+    use candid_parser::parse_idl_value;
+    let mut helper = crate::helper::test_helper();
+    let some = parse_idl_value("record { result = opt variant { NeuronId = 42 : nat64 } }")?;
+    let none = parse_idl_value("record { result = null }")?;
+    helper.env.0.insert("some".to_string(), some);
+    helper.env.0.insert("none".to_string(), none);
+    let eval = |s: &str| s.parse::<Exp>().unwrap().eval(&helper);
+    // `?` followed by `.` unwraps the opt and then projects, failing on null.
+    assert_eq!(eval("some.result?.NeuronId")?, IDLValue::Nat64(42));
+    assert!(eval("none.result?.NeuronId").is_err());
+    assert!(eval("some.result?.Missing").is_err());
+    // `.?` evaluates to null on a null opt or a missing field.
+    assert_eq!(eval("some.result.?NeuronId")?, IDLValue::Nat64(42));
+    assert_eq!(eval("none.result.?NeuronId")?, IDLValue::Null);
+    assert_eq!(eval("some.result.?Missing")?, IDLValue::Null);
+    Ok(())
 }
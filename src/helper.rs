@@ -1,6 +1,6 @@
 use crate::exp::Exp;
 use crate::token::{Token, Tokenizer};
-use crate::utils::{fetch_metadata, random_value, str_to_principal};
+use crate::utils::{fetch_metadata, random_value, runtime, str_to_principal, RetryPolicy};
 use candid::{
     types::value::{IDLField, IDLValue, VariantValue},
     types::{Function, Label, Type, TypeInner},
@@ -17,12 +17,17 @@ use rustyline::Context;
 use rustyline_derive::Helper;
 use std::borrow::Cow::{self, Borrowed, Owned};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
 use std::sync::Arc;
-use tokio::runtime::Runtime;
 
+/// Fetched canister interfaces.
 #[derive(Default, Clone)]
-pub struct CanisterMap(pub BTreeMap<Principal, CanisterInfo>);
+pub struct CanisterMap {
+    pub map: BTreeMap<Principal, CanisterInfo>,
+    /// TTL of the on-disk candid cache, which is disabled when `None`.
+    pub ttl: Option<std::time::Duration>,
+}
 #[derive(Default, Clone)]
 pub struct IdentityMap(pub BTreeMap<String, Arc<dyn Identity>>);
 #[derive(Default, Clone)]
@@ -39,6 +44,8 @@ pub struct CanisterInfo {
 #[derive(Clone)]
 pub enum OfflineOutput {
     Json,
+    Cbor,
+    Curl(String),
     Ascii(String),
     Png(String),
     PngNoUrl,
@@ -46,11 +53,31 @@ pub enum OfflineOutput {
 }
 impl CanisterMap {
     pub fn get(&mut self, agent: &Agent, id: &Principal) -> anyhow::Result<&CanisterInfo> {
-        if !self.0.contains_key(id) {
-            let info = fetch_actor(agent, *id)?;
-            self.0.insert(*id, info);
+        if !self.map.contains_key(id) {
+            let candid = match self.ttl.and_then(|ttl| CachedCandid::read(id, ttl)) {
+                Some(candid) => candid,
+                None => {
+                    let candid = fetch_actor(agent, *id)?;
+                    if self.ttl.is_some() && candid.candid.is_some() {
+                        candid.write(id);
+                    }
+                    candid
+                }
+            };
+            self.map.insert(*id, candid.to_canister_info(id)?);
+        }
+        Ok(self.map.get(id).unwrap())
+    }
+    /// Drop the interface of `id`, including the on-disk cache, so that the next `get` fetches it again.
+    /// The management canister is skipped, as its preloaded interface cannot be fetched from the chain.
+    pub fn evict(&mut self, id: &Principal) {
+        if *id == Principal::management_canister() {
+            return;
+        }
+        self.map.remove(id);
+        if let Some(path) = CachedCandid::path(id) {
+            let _ = std::fs::remove_file(path);
         }
-        Ok(self.0.get(id).unwrap())
     }
 }
 impl CanisterInfo {
@@ -85,13 +112,29 @@ pub struct MyHelper {
     pub current_identity: String,
     pub agent_url: String,
     pub agent: Agent,
+    /// The root key the agent is built with, restored by `config "fetch_root_key = false"`.
+    pub ic_root_key: Vec<u8>,
     pub config: Configs,
     pub env: Env,
     pub func_env: FuncEnv,
     pub base_path: std::path::PathBuf,
+    /// Scripts already run by `include`, so that a shared script is only run once.
+    pub included: BTreeSet<std::path::PathBuf>,
     pub messages: RefCell<Vec<crate::offline::IngressWithStatus>>,
     pub verbose: bool,
     pub default_effective_canister_id: Principal,
+    pub retry: RetryPolicy,
+    pub timeout: Option<std::time::Duration>,
+    pub keep_going: bool,
+    pub profile_queries: bool,
+    pub test_stats: Rc<RefCell<TestStats>>,
+}
+
+#[derive(Default)]
+pub struct TestStats {
+    pub passed: usize,
+    pub failed: Vec<String>,
+    pub exit_code: Option<i32>,
 }
 
 impl MyHelper {
@@ -109,13 +152,107 @@ impl MyHelper {
             env: self.env.clone(),
             func_env: self.func_env.clone(),
             base_path: self.base_path.clone(),
+            included: self.included.clone(),
             agent: self.agent.clone(),
+            ic_root_key: self.ic_root_key.clone(),
             agent_url: self.agent_url.clone(),
             offline: self.offline.clone(),
             messages: self.messages.clone(),
             verbose: self.verbose,
             default_effective_canister_id: self.default_effective_canister_id,
+            retry: self.retry.clone(),
+            timeout: self.timeout,
+            keep_going: self.keep_going,
+            profile_queries: self.profile_queries,
+            test_stats: self.test_stats.clone(),
+        }
+    }
+    /// Set the config in TOML format. The `[retry]` table and `timeout` set the retry policy and the deadline
+    /// of canister calls, `keep_going` records failed assertions instead of stopping the script,
+    /// `candid_cache_ttl` caches fetched candid interfaces on disk for the given number of seconds,
+    /// `profile_queries` calls query methods of profiled canisters as update calls,
+    /// `fetch_root_key` sets whether to trust the root key of a non-mainnet replica, and the rest is used for random value generation.
+    pub fn set_config(&mut self, conf: &str) -> anyhow::Result<()> {
+        let mut config = conf.parse::<Configs>()?;
+        self.retry = match config.0.remove("retry") {
+            Some(retry) => {
+                let retry = retry
+                    .as_table()
+                    .ok_or_else(|| anyhow::anyhow!("retry config expects a table"))?;
+                let get = |key: &str, default: u64| -> anyhow::Result<u64> {
+                    match retry.get(key) {
+                        None => Ok(default),
+                        Some(v) => v
+                            .as_integer()
+                            .and_then(|n| u64::try_from(n).ok())
+                            .ok_or_else(|| {
+                                anyhow::anyhow!("retry.{key} expects a non-negative integer")
+                            }),
+                    }
+                };
+                let default = RetryPolicy::default();
+                RetryPolicy {
+                    max_attempts: get("max_attempts", default.max_attempts as u64)? as u32,
+                    base_delay: std::time::Duration::from_millis(get(
+                        "base_delay_ms",
+                        default.base_delay.as_millis() as u64,
+                    )?),
+                }
+            }
+            None => RetryPolicy::default(),
+        };
+        self.timeout = match config.0.remove("timeout") {
+            Some(t) => {
+                let secs = t
+                    .as_float()
+                    .or_else(|| t.as_integer().map(|n| n as f64))
+                    .filter(|secs| *secs > 0.0)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("timeout expects a positive number of seconds")
+                    })?;
+                Some(std::time::Duration::from_secs_f64(secs))
+            }
+            None => None,
+        };
+        self.keep_going = match config.0.remove("keep_going") {
+            Some(v) => v
+                .as_bool()
+                .ok_or_else(|| anyhow::anyhow!("keep_going expects a boolean"))?,
+            None => false,
+        };
+        self.profile_queries = match config.0.remove("profile_queries") {
+            Some(v) => v
+                .as_bool()
+                .ok_or_else(|| anyhow::anyhow!("profile_queries expects a boolean"))?,
+            None => false,
+        };
+        self.canister_map.borrow_mut().ttl = match config.0.remove("candid_cache_ttl") {
+            Some(t) => {
+                let secs = t
+                    .as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("candid_cache_ttl expects a non-negative number of seconds")
+                    })?;
+                Some(std::time::Duration::from_secs(secs)).filter(|ttl| !ttl.is_zero())
+            }
+            None => None,
+        };
+        if let Some(v) = config.0.remove("fetch_root_key") {
+            let fetch = v
+                .as_bool()
+                .ok_or_else(|| anyhow::anyhow!("fetch_root_key expects a boolean"))?;
+            if !fetch {
+                self.agent.set_root_key(self.ic_root_key.clone());
+            } else if self.offline.is_none() {
+                if self.is_mainnet() {
+                    return Err(anyhow::anyhow!("Cannot fetch root key from mainnet"));
+                }
+                runtime().block_on(self.agent.fetch_root_key())?;
+            }
         }
+        self.config = config;
+        Ok(())
     }
     pub fn new(
         agent: Agent,
@@ -123,8 +260,7 @@ impl MyHelper {
         offline: Option<OfflineOutput>,
         verbose: bool,
     ) -> Self {
-        let runtime = Runtime::new().expect("Unable to create a runtime");
-        let default_effective_canister_id = runtime
+        let default_effective_canister_id = runtime()
             .block_on(async {
                 use serde_with::base64::Base64;
                 #[serde_with::serde_as]
@@ -149,6 +285,22 @@ impl MyHelper {
                 }
             })
             .unwrap_or(Principal::management_canister());
+        Self::with_effective_canister_id(
+            agent,
+            agent_url,
+            offline,
+            verbose,
+            default_effective_canister_id,
+        )
+    }
+    fn with_effective_canister_id(
+        agent: Agent,
+        agent_url: String,
+        offline: Option<OfflineOutput>,
+        verbose: bool,
+        default_effective_canister_id: Principal,
+    ) -> Self {
+        let ic_root_key = agent.read_root_key();
         let mut res = MyHelper {
             completer: FilenameCompleter::new(),
             highlighter: MatchingBracketHighlighter::new(),
@@ -162,18 +314,25 @@ impl MyHelper {
             env: Env::default(),
             func_env: FuncEnv::default(),
             base_path: std::env::current_dir().unwrap(),
+            included: BTreeSet::new(),
             messages: Vec::new().into(),
             agent,
+            ic_root_key,
             agent_url,
             offline,
             verbose,
             default_effective_canister_id,
+            retry: RetryPolicy::default(),
+            timeout: None,
+            keep_going: false,
+            profile_queries: false,
+            test_stats: Rc::new(RefCell::new(TestStats::default())),
         };
         res.fetch_root_key_if_needed().unwrap();
         res.load_prelude().unwrap();
         res
     }
-    fn is_mainnet(&self) -> bool {
+    pub fn is_mainnet(&self) -> bool {
         self.agent_url == "https://icp0.io" || self.agent_url == "https://ic0.app"
     }
     fn load_prelude(&mut self) -> anyhow::Result<()> {
@@ -221,7 +380,7 @@ impl MyHelper {
     ) -> anyhow::Result<()> {
         let mut canister_map = self.canister_map.borrow_mut();
         if let Some(did_file) = did_file {
-            canister_map.0.insert(
+            canister_map.map.insert(
                 id,
                 did_to_canister_info(&name, FileSource::Text(did_file), None)?,
             );
@@ -231,13 +390,13 @@ impl MyHelper {
     }
     pub fn fetch_root_key_if_needed(&mut self) -> anyhow::Result<()> {
         if self.offline.is_none() && !self.is_mainnet() {
-            let runtime = Runtime::new().expect("Unable to create a runtime");
-            runtime.block_on(self.agent.fetch_root_key())?;
+            runtime().block_on(self.agent.fetch_root_key())?;
         };
         Ok(())
     }
     pub fn dump_ingress(&self) -> anyhow::Result<()> {
-        crate::offline::dump_ingress(&self.messages.borrow())
+        let format = self.offline.as_ref().unwrap_or(&OfflineOutput::Json);
+        crate::offline::dump_ingress(&self.messages.borrow(), format)
     }
 }
 
@@ -265,7 +424,7 @@ impl Partial {
 
 fn partial_parse(line: &str, pos: usize, helper: &MyHelper) -> Option<(usize, Partial)> {
     let (start, _) = extract_word(line, pos, None, |c| c == ' ');
-    let iter = Tokenizer::new(&line[start..pos]);
+    let iter = Tokenizer::new_exp(&line[start..pos]);
     let mut tokens = Vec::new();
     let mut pos_start = 0;
     for v in iter {
@@ -527,40 +686,78 @@ impl Validator for MyHelper {
     }
 }
 
-#[tokio::main]
-async fn fetch_actor(agent: &Agent, canister_id: Principal) -> anyhow::Result<CanisterInfo> {
-    let response = fetch_metadata(agent, canister_id, "metadata/candid:service").await;
-    let profiling = fetch_metadata(agent, canister_id, "metadata/name")
-        .await
-        .ok()
-        .as_ref()
-        .and_then(|bytes| Decode!(bytes, BTreeMap<u16, String>).ok());
-    let candid = match response {
-        Ok(blob) => std::str::from_utf8(&blob)?.to_owned(),
-        Err(_) => {
-            let response = agent
-                .query(&canister_id, "__get_candid_interface_tmp_hack")
-                .with_arg(Encode!()?)
-                .call()
-                .await;
-            match response {
-                Ok(response) => Decode!(&response, String)?,
-                Err(_) => {
-                    return Ok(CanisterInfo {
-                        env: Default::default(),
-                        methods: Default::default(),
-                        init: None,
-                        profiling,
-                    })
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedCandid {
+    candid: Option<String>,
+    profiling: Option<BTreeMap<u16, String>>,
+}
+impl CachedCandid {
+    fn path(id: &Principal) -> Option<std::path::PathBuf> {
+        Some(candid_cache_dir()?.join(format!("{id}.json")))
+    }
+    fn read(id: &Principal, ttl: std::time::Duration) -> Option<Self> {
+        let path = Self::path(id)?;
+        let age = std::fs::metadata(&path)
+            .ok()?
+            .modified()
+            .ok()?
+            .elapsed()
+            .ok()?;
+        if age > ttl {
+            return None;
+        }
+        serde_json::from_slice(&std::fs::read(path).ok()?).ok()
+    }
+    fn write(&self, id: &Principal) {
+        // The cache is best effort, failing to write it should not fail the fetch.
+        if let Some(path) = Self::path(id) {
+            let _ = std::fs::create_dir_all(path.parent().unwrap())
+                .and_then(|_| std::fs::write(&path, serde_json::to_vec(self).unwrap()));
+        }
+    }
+    fn to_canister_info(&self, id: &Principal) -> anyhow::Result<CanisterInfo> {
+        match &self.candid {
+            Some(candid) => did_to_canister_info(
+                &format!("did file for {id}"),
+                FileSource::Text(candid),
+                self.profiling.clone(),
+            ),
+            None => Ok(CanisterInfo {
+                env: Default::default(),
+                methods: Default::default(),
+                init: None,
+                profiling: self.profiling.clone(),
+            }),
+        }
+    }
+}
+pub fn candid_cache_dir() -> Option<std::path::PathBuf> {
+    Some(dirs::cache_dir()?.join("ic-repl").join("candid"))
+}
+fn fetch_actor(agent: &Agent, canister_id: Principal) -> anyhow::Result<CachedCandid> {
+    runtime().block_on(async move {
+        let response = fetch_metadata(agent, canister_id, "metadata/candid:service").await;
+        let profiling = fetch_metadata(agent, canister_id, "metadata/name")
+            .await
+            .ok()
+            .as_ref()
+            .and_then(|bytes| Decode!(bytes, BTreeMap<u16, String>).ok());
+        let candid = match response {
+            Ok(blob) => Some(std::str::from_utf8(&blob)?.to_owned()),
+            Err(_) => {
+                let response = agent
+                    .query(&canister_id, "__get_candid_interface_tmp_hack")
+                    .with_arg(Encode!()?)
+                    .call()
+                    .await;
+                match response {
+                    Ok(response) => Some(Decode!(&response, String)?),
+                    Err(_) => None,
                 }
             }
-        }
-    };
-    did_to_canister_info(
-        &format!("did file for {canister_id}"),
-        FileSource::Text(&candid),
-        profiling,
-    )
+        };
+        Ok(CachedCandid { candid, profiling })
+    })
 }
 
 pub enum FileSource<'a> {
@@ -619,14 +816,29 @@ impl Env {
             .map(|(name, id)| (name.clone(), id.to_text()))
             .collect()
     }
+    /// Find the variable name bound to a principal, ignoring `_` and internal variables.
+    pub fn find_principal_name(&self, id: &Principal) -> Option<&str> {
+        self.0.iter().find_map(|(name, value)| match value {
+            IDLValue::Principal(v) | IDLValue::Service(v) if v == id && !name.starts_with('_') => {
+                Some(name.as_str())
+            }
+            _ => None,
+        })
+    }
+}
+
+/// A mainnet helper for unit tests, which skips the topology request so that no network I/O happens.
+#[cfg(test)]
+pub fn test_helper() -> MyHelper {
+    let url = "https://icp0.io".to_string();
+    let agent = Agent::builder().with_url(url.clone()).build().unwrap();
+    MyHelper::with_effective_canister_id(agent, url, None, false, Principal::management_canister())
 }
 
 #[test]
 fn test_partial_parse() -> anyhow::Result<()> {
     use candid_parser::parse_idl_value;
-    let url = "https://icp0.io".to_string();
-    let agent = Agent::builder().with_url(url.clone()).build()?;
-    let mut helper = MyHelper::new(agent, url, None, false);
+    let mut helper = test_helper();
     helper.env.0.insert(
         "a".to_string(),
         parse_idl_value("opt record { variant {b=vec{1;2;3}}; 42; f1=42;42=35;a1=30}")?,
@@ -0,0 +1,420 @@
+use crate::utils::{as_blob, cast_type, field, get_field, record};
+use anyhow::{anyhow, Result};
+use candid::{
+    types::value::{IDLField, IDLValue, VariantValue},
+    types::{Label, TypeInner},
+    Principal,
+};
+
+/// Optional input fields can be provided either as `opt v` or as `v` directly.
+fn opt_field<'a>(fs: &'a [IDLField], name: &'a str) -> Option<&'a IDLValue> {
+    match get_field(fs, name)? {
+        IDLValue::Opt(v) => Some(v),
+        IDLValue::None | IDLValue::Null => None,
+        v => Some(v),
+    }
+}
+
+fn opt_blob(fs: &[IDLField], name: &str) -> Result<IDLValue> {
+    Ok(match opt_field(fs, name) {
+        Some(IDLValue::Text(str)) => {
+            IDLValue::Opt(Box::new(IDLValue::Blob(str.as_bytes().to_vec())))
+        }
+        Some(v) => IDLValue::Opt(Box::new(IDLValue::Blob(
+            as_blob(v.clone()).map_err(|_| anyhow!("{name} expects a blob"))?,
+        ))),
+        None => IDLValue::None,
+    })
+}
+
+fn opt_nat(fs: &[IDLField], name: &str, ty: TypeInner) -> Result<IDLValue> {
+    Ok(match opt_field(fs, name) {
+        Some(v) => IDLValue::Opt(Box::new(
+            cast_type(v.clone(), &ty.into()).map_err(|_| anyhow!("{name} expects a nat"))?,
+        )),
+        None => IDLValue::None,
+    })
+}
+
+pub fn account(owner: Principal, subaccount: Option<Vec<u8>>) -> IDLValue {
+    record(vec![
+        field("owner", IDLValue::Principal(owner)),
+        field(
+            "subaccount",
+            match subaccount {
+                Some(sub) => IDLValue::Opt(Box::new(IDLValue::Blob(sub))),
+                None => IDLValue::None,
+            },
+        ),
+    ])
+}
+
+/// Build an ICRC-1 `TransferArg` from `record { to; subaccount; amount; memo; fee; from_subaccount; created_at_time }`.
+/// Only `to` and `amount` are required.
+pub fn transfer_arg(fs: &[IDLField]) -> Result<IDLValue> {
+    let Some(IDLValue::Principal(to)) = get_field(fs, "to") else {
+        return Err(anyhow!("to expects a principal"));
+    };
+    let amount = get_field(fs, "amount").ok_or_else(|| anyhow!("amount is required"))?;
+    let amount = cast_type(amount.clone(), &TypeInner::Nat.into())
+        .map_err(|_| anyhow!("amount expects a non-negative nat"))?;
+    let subaccount = match opt_blob(fs, "subaccount")? {
+        IDLValue::Opt(sub) => match *sub {
+            IDLValue::Blob(sub) => Some(sub),
+            _ => unreachable!(),
+        },
+        _ => None,
+    };
+    Ok(record(vec![
+        field("to", account(*to, subaccount)),
+        field("amount", amount),
+        field("from_subaccount", opt_blob(fs, "from_subaccount")?),
+        field("fee", opt_nat(fs, "fee", TypeInner::Nat)?),
+        field("memo", opt_blob(fs, "memo")?),
+        field(
+            "created_at_time",
+            opt_nat(fs, "created_at_time", TypeInner::Nat64)?,
+        ),
+    ]))
+}
+
+/// Returns the block index from an ICRC-1 transfer `Result`, or errors with the `Err` variant.
+pub fn check_result(result: &IDLValue) -> Result<IDLValue> {
+    let IDLValue::Variant(VariantValue(f, _)) = result else {
+        return Err(anyhow!("icrc1_check expects a result variant"));
+    };
+    match &f.id {
+        Label::Named(tag) if tag == "Ok" => Ok(f.val.clone()),
+        Label::Named(tag) if tag == "Err" => Err(anyhow!("ICRC-1 call failed: {}", f.val)),
+        _ => Err(anyhow!("icrc1_check expects variant {{ Ok; Err }}")),
+    }
+}
+
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut res = String::new();
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for b in bytes {
+        buffer = (buffer << 8) | *b as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            res.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        res.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    res
+}
+
+fn account_checksum(owner: &Principal, subaccount: &[u8; 32]) -> String {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount);
+    base32(&hasher.finalize().to_be_bytes())
+}
+
+/// Encode an ICRC-1 account in the textual format `<owner>-<checksum>.<subaccount hex without leading zeros>`.
+/// The default subaccount is omitted.
+pub fn encode_account(owner: &Principal, subaccount: Option<&[u8]>) -> Result<String> {
+    let sub: [u8; 32] = match subaccount {
+        None => return Ok(owner.to_text()),
+        Some(sub) => sub
+            .try_into()
+            .map_err(|_| anyhow!("subaccount must be 32 bytes"))?,
+    };
+    if sub == [0; 32] {
+        return Ok(owner.to_text());
+    }
+    let hex = hex::encode(sub);
+    Ok(format!(
+        "{}-{}.{}",
+        owner.to_text(),
+        account_checksum(owner, &sub),
+        hex.trim_start_matches('0')
+    ))
+}
+
+pub fn decode_account(text: &str) -> Result<IDLValue> {
+    let Some((rest, hex)) = text.rsplit_once('.') else {
+        let owner = Principal::from_text(text)?;
+        return Ok(account(owner, None));
+    };
+    if hex.is_empty() || hex.starts_with('0') || hex.len() > 64 {
+        return Err(anyhow!("{text} has a non-canonical subaccount"));
+    }
+    let (owner, checksum) = rest
+        .rsplit_once('-')
+        .ok_or_else(|| anyhow!("{text} is missing the checksum"))?;
+    let owner = Principal::from_text(owner)?;
+    let mut sub = [0u8; 32];
+    let bytes = hex::decode(format!("{hex:0>64}"))
+        .map_err(|e| anyhow!("{text} has an invalid subaccount: {e}"))?;
+    sub.copy_from_slice(&bytes);
+    if account_checksum(&owner, &sub) != checksum {
+        return Err(anyhow!("{text} has an invalid checksum"));
+    }
+    Ok(account(owner, Some(sub.to_vec())))
+}
+
+#[test]
+fn test_icrc_account() -> Result<()> {
+    let owner =
+        Principal::from_text("k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae")?;
+    let sub: Vec<u8> = (1..=32).collect();
+    let text = "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20";
+    assert_eq!(encode_account(&owner, Some(&sub))?, text);
+    assert_eq!(decode_account(text)?, account(owner, Some(sub)));
+    assert_eq!(encode_account(&owner, Some(&[0; 32]))?, owner.to_text());
+    assert_eq!(encode_account(&owner, None)?, owner.to_text());
+    assert_eq!(decode_account(&owner.to_text())?, account(owner, None));
+    assert!(decode_account(&text.replace("dfxgiyy", "dfxgiya")).is_err());
+    assert!(decode_account(&format!("{}-dfxgiyy.", owner.to_text())).is_err());
+    assert!(decode_account(&format!("{}-dfxgiyy.01", owner.to_text())).is_err());
+    Ok(())
+}
+
+pub fn balance_of(
+    agent: &ic_agent::Agent,
+    ledger: &Principal,
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+) -> Result<candid::Nat> {
+    use candid::{CandidType, Decode, Encode};
+    #[derive(CandidType)]
+    struct Account {
+        owner: Principal,
+        subaccount: Option<Vec<u8>>,
+    }
+    let arg = Account { owner, subaccount };
+    let bytes = crate::utils::runtime().block_on(
+        agent
+            .query(ledger, "icrc1_balance_of")
+            .with_arg(Encode!(&arg)?)
+            .with_effective_canister_id(*ledger)
+            .call(),
+    )?;
+    Ok(Decode!(&bytes, candid::Nat)?)
+}
+
+const ICRC3_DID: &str = r#"
+type Value = variant {
+  Blob : blob;
+  Text : text;
+  Nat : nat;
+  Int : int;
+  Array : vec Value;
+  Map : vec record { text; Value };
+};
+type GetBlocksArgs = vec record { start : nat; length : nat };
+type GetBlocksResult = record {
+  log_length : nat;
+  blocks : vec record { id : nat; block : Value };
+  archived_blocks : vec record {
+    args : GetBlocksArgs;
+    callback : func (GetBlocksArgs) -> (GetBlocksResult) query;
+  };
+};
+service : {
+  icrc3_get_blocks : (GetBlocksArgs) -> (GetBlocksResult) query;
+}
+"#;
+
+/// Fetch blocks `[start, start + length)` from an ICRC-3 ledger, following the archive callbacks.
+/// Returns a vec of `record { id : nat; block : Value }` sorted by block id.
+pub fn get_blocks(
+    agent: &ic_agent::Agent,
+    ledger: &Principal,
+    start: IDLValue,
+    length: IDLValue,
+) -> Result<IDLValue> {
+    use crate::helper::{did_to_canister_info, FileSource};
+    use candid::IDLArgs;
+    let info = did_to_canister_info("icrc3", FileSource::Text(ICRC3_DID), None)?;
+    let func = &info.methods["icrc3_get_blocks"];
+    let range = record(vec![
+        field("start", cast_type(start, &TypeInner::Nat.into())?),
+        field("length", cast_type(length, &TypeInner::Nat.into())?),
+    ]);
+    let mut queue = vec![(
+        *ledger,
+        "icrc3_get_blocks".to_string(),
+        IDLValue::Vec(vec![range]),
+    )];
+    let mut blocks = Vec::new();
+    while let Some((canister_id, method, args)) = queue.pop() {
+        let args = IDLArgs::new(&[args]).to_bytes_with_types(&info.env, &func.args)?;
+        let bytes = crate::utils::runtime().block_on(
+            agent
+                .query(&canister_id, &method)
+                .with_arg(args)
+                .with_effective_canister_id(canister_id)
+                .call(),
+        )?;
+        let mut res = IDLArgs::from_bytes_with_types(&bytes, &info.env, &func.rets)?;
+        let IDLValue::Record(fs) = res.args.pop().unwrap() else {
+            return Err(anyhow!("unexpected icrc3_get_blocks result"));
+        };
+        if let Some(IDLValue::Vec(vs)) = get_field(&fs, "blocks") {
+            blocks.extend(vs.iter().cloned());
+        }
+        if let Some(IDLValue::Vec(archives)) = get_field(&fs, "archived_blocks") {
+            for archive in archives {
+                let IDLValue::Record(archive) = archive else {
+                    return Err(anyhow!("unexpected archived_blocks entry"));
+                };
+                let (Some(args), Some(IDLValue::Func(id, method))) =
+                    (get_field(archive, "args"), get_field(archive, "callback"))
+                else {
+                    return Err(anyhow!("unexpected archived_blocks entry"));
+                };
+                queue.push((*id, method.clone(), args.clone()));
+            }
+        }
+    }
+    let block_id = |v: &IDLValue| match v {
+        IDLValue::Record(fs) => match get_field(fs, "id") {
+            Some(IDLValue::Nat(n)) => n.clone(),
+            _ => candid::Nat::from(0u8),
+        },
+        _ => candid::Nat::from(0u8),
+    };
+    blocks.sort_by_key(block_id);
+    Ok(IDLValue::Vec(blocks))
+}
+
+/// Representation-independent hash of an ICRC-3 `Value`.
+pub fn value_hash(v: &IDLValue) -> Result<[u8; 32]> {
+    use sha2::{Digest, Sha256};
+    let IDLValue::Variant(VariantValue(f, _)) = v else {
+        return Err(anyhow!("{v} is not an ICRC-3 Value"));
+    };
+    let Label::Named(tag) = &f.id else {
+        return Err(anyhow!("{v} is not an ICRC-3 Value"));
+    };
+    let mut bytes = Vec::new();
+    match (tag.as_str(), &f.val) {
+        ("Blob", v) => bytes = as_blob(v.clone())?,
+        ("Text", IDLValue::Text(s)) => bytes = s.as_bytes().to_vec(),
+        ("Nat", v) => match cast_type(v.clone(), &TypeInner::Nat.into())? {
+            IDLValue::Nat(n) => n.encode(&mut bytes)?,
+            _ => unreachable!(),
+        },
+        ("Int", v) => match cast_type(v.clone(), &TypeInner::Int.into())? {
+            IDLValue::Int(n) => n.encode(&mut bytes)?,
+            _ => unreachable!(),
+        },
+        ("Array", IDLValue::Vec(vs)) => {
+            for v in vs {
+                bytes.extend(value_hash(v)?);
+            }
+        }
+        ("Map", IDLValue::Vec(entries)) => {
+            let mut pairs = Vec::with_capacity(entries.len());
+            for entry in entries {
+                let IDLValue::Record(kv) = entry else {
+                    return Err(anyhow!(
+                        "Map entry {entry} is not a record {{ text; Value }}"
+                    ));
+                };
+                let [IDLField {
+                    val: IDLValue::Text(k),
+                    ..
+                }, IDLField { val, .. }] = kv.as_slice()
+                else {
+                    return Err(anyhow!(
+                        "Map entry {entry} is not a record {{ text; Value }}"
+                    ));
+                };
+                let mut pair = Sha256::digest(k.as_bytes()).to_vec();
+                pair.extend(value_hash(val)?);
+                pairs.push(pair);
+            }
+            pairs.sort();
+            bytes = pairs.concat();
+        }
+        _ => return Err(anyhow!("{v} is not an ICRC-3 Value")),
+    }
+    Ok(Sha256::digest(&bytes).into())
+}
+
+/// Check that the `phash` of each block equals the hash of its predecessor.
+/// Accepts the output of `get_blocks` or a plain vec of `Value`.
+/// Returns `None` when the chain is consistent, otherwise the id of the first offending block.
+pub fn verify_chain(blocks: &[IDLValue]) -> Result<Option<candid::Nat>> {
+    let mut prev: Option<[u8; 32]> = None;
+    for (i, v) in blocks.iter().enumerate() {
+        let (id, block) = match v {
+            IDLValue::Record(fs) => match (get_field(fs, "id"), get_field(fs, "block")) {
+                (Some(id), Some(block)) => (cast_type(id.clone(), &TypeInner::Nat.into())?, block),
+                _ => return Err(anyhow!("block {i} is not a record {{ id; block }}")),
+            },
+            v => (IDLValue::Nat(candid::Nat::from(i)), v),
+        };
+        let IDLValue::Nat(id) = id else {
+            unreachable!()
+        };
+        let IDLValue::Variant(VariantValue(f, _)) = block else {
+            return Err(anyhow!("block {id} is not an ICRC-3 Value"));
+        };
+        let phash = match &f.val {
+            IDLValue::Vec(entries) => entries.iter().find_map(|entry| match entry {
+                IDLValue::Record(kv) => match kv.as_slice() {
+                    [IDLField {
+                        val: IDLValue::Text(k),
+                        ..
+                    }, IDLField { val, .. }]
+                        if k == "phash" =>
+                    {
+                        Some(val)
+                    }
+                    _ => None,
+                },
+                _ => None,
+            }),
+            _ => None,
+        };
+        let phash = match phash {
+            Some(IDLValue::Variant(VariantValue(f, _))) => Some(as_blob(f.val.clone())?),
+            Some(_) => return Ok(Some(id)),
+            None => None,
+        };
+        match (&prev, phash) {
+            (Some(prev), Some(phash)) if prev[..] == phash[..] => (),
+            (None, _) => (),
+            _ => return Ok(Some(id)),
+        }
+        prev = Some(value_hash(block)?);
+    }
+    Ok(None)
+}
+
+#[test]
+fn test_icrc3_verify() -> Result<()> {
+    let parse = |s: &str| candid_parser::parse_idl_value(s).unwrap();
+    assert_eq!(
+        hex::encode(value_hash(&parse("variant { Nat = 42 : nat }"))?),
+        "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"
+    );
+    assert_eq!(
+        hex::encode(value_hash(&parse(r#"variant { Text = "Hello, World!" }"#))?),
+        "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+    );
+    let b0 = parse(r#"variant { Map = vec { record { "ts"; variant { Nat = 1 : nat } } } }"#);
+    let block = |phash: [u8; 32]| {
+        parse(&format!(
+            r#"variant {{ Map = vec {{ record {{ "phash"; variant {{ Blob = blob "{}" }} }}; record {{ "ts"; variant {{ Nat = 2 : nat }} }} }} }}"#,
+            phash
+                .iter()
+                .map(|b| format!("\\{b:02x}"))
+                .collect::<String>()
+        ))
+    };
+    let b1 = block(value_hash(&b0)?);
+    assert_eq!(verify_chain(&[b0.clone(), b1])?, None);
+    let bad = block([0; 32]);
+    assert_eq!(verify_chain(&[b0, bad])?, Some(candid::Nat::from(1u8)));
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Result};
+use candid::types::value::{IDLField, IDLValue};
+use candid::types::Label;
+use serde::ser::{Error as _, Serialize, Serializer};
+use serde_json::value::RawValue;
+use std::collections::BTreeMap;
+
+/// Convert JSON into a candid value. Objects become records, and integers become numbers of undetermined type,
+/// so that big integers keep their precision. Numeric keys become field ids.
+pub fn from_json(json: &str) -> Result<IDLValue> {
+    let v: &RawValue = serde_json::from_str(json)?;
+    json_to_value(v)
+}
+
+/// Walks the JSON text without going through `serde_json::Value`, which would round integers beyond 64 bits.
+fn json_to_value(v: &RawValue) -> Result<IDLValue> {
+    let s = v.get();
+    Ok(match s.as_bytes()[0] {
+        b'n' => IDLValue::Null,
+        b't' | b'f' => IDLValue::Bool(serde_json::from_str(s)?),
+        b'"' => IDLValue::Text(serde_json::from_str(s)?),
+        b'[' => {
+            let vs: Vec<&RawValue> = serde_json::from_str(s)?;
+            IDLValue::Vec(
+                vs.into_iter()
+                    .map(json_to_value)
+                    .collect::<Result<Vec<_>>>()?,
+            )
+        }
+        b'{' => {
+            let map: BTreeMap<String, &RawValue> = serde_json::from_str(s)?;
+            let mut fs = Vec::with_capacity(map.len());
+            for (k, v) in map {
+                // numeric keys come from tuple or unnamed fields in `to_json`
+                let id = match k.parse::<u32>() {
+                    Ok(n) => Label::Id(n),
+                    Err(_) => Label::Named(k),
+                };
+                fs.push(IDLField {
+                    id,
+                    val: json_to_value(v)?,
+                });
+            }
+            fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+            candid::utils::check_unique(fs.iter().map(|f| &f.id))
+                .map_err(|e| anyhow!("JSON keys have a hash collision: {e}"))?;
+            IDLValue::Record(fs)
+        }
+        _ if s.contains(['.', 'e', 'E']) => IDLValue::Float64(s.parse()?),
+        _ => IDLValue::Number(s.to_string()),
+    })
+}
+
+#[derive(Clone, Copy)]
+pub enum BlobFormat {
+    Base64,
+    Hex,
+    Array,
+}
+
+impl std::str::FromStr for BlobFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "base64" => Ok(BlobFormat::Base64),
+            "hex" => Ok(BlobFormat::Hex),
+            "array" => Ok(BlobFormat::Array),
+            _ => Err(anyhow!(
+                "blob_format expects \"base64\", \"hex\" or \"array\""
+            )),
+        }
+    }
+}
+
+/// Convert a candid value into JSON text. Records become objects, variants become single-key objects,
+/// opt values become the inner value or null, and principals become text.
+pub fn to_json(v: &IDLValue, blob: BlobFormat) -> Result<String> {
+    Ok(serde_json::to_string(&Json(v, blob))?)
+}
+
+fn label_to_key(id: &Label) -> String {
+    match id {
+        Label::Named(name) => name.clone(),
+        Label::Id(n) | Label::Unnamed(n) => n.to_string(),
+    }
+}
+
+/// Serializes a candid value as JSON. Big integers are written out as raw JSON numbers to keep their precision.
+struct Json<'a>(&'a IDLValue, BlobFormat);
+
+fn raw_number<S: Serializer>(n: String, s: S) -> Result<S::Ok, S::Error> {
+    RawValue::from_string(n)
+        .map_err(S::Error::custom)?
+        .serialize(s)
+}
+
+impl Serialize for Json<'_> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let Json(v, blob) = *self;
+        match v {
+            IDLValue::Null | IDLValue::None | IDLValue::Reserved => s.serialize_unit(),
+            IDLValue::Bool(b) => s.serialize_bool(*b),
+            IDLValue::Opt(v) => Json(v, blob).serialize(s),
+            IDLValue::Number(n) => {
+                let n = n
+                    .replace('_', "")
+                    .parse::<candid::Int>()
+                    .map_err(S::Error::custom)?;
+                raw_number(n.to_string(), s)
+            }
+            IDLValue::Nat(n) => raw_number(n.to_string(), s),
+            IDLValue::Int(n) => raw_number(n.to_string(), s),
+            IDLValue::Nat8(n) => s.serialize_u8(*n),
+            IDLValue::Nat16(n) => s.serialize_u16(*n),
+            IDLValue::Nat32(n) => s.serialize_u32(*n),
+            IDLValue::Nat64(n) => s.serialize_u64(*n),
+            IDLValue::Int8(n) => s.serialize_i8(*n),
+            IDLValue::Int16(n) => s.serialize_i16(*n),
+            IDLValue::Int32(n) => s.serialize_i32(*n),
+            IDLValue::Int64(n) => s.serialize_i64(*n),
+            // non-finite floats become null
+            IDLValue::Float32(f) => s.serialize_f64(*f as f64),
+            IDLValue::Float64(f) => s.serialize_f64(*f),
+            IDLValue::Text(t) => s.serialize_str(t),
+            IDLValue::Principal(id) | IDLValue::Service(id) => s.serialize_str(&id.to_text()),
+            IDLValue::Func(id, meth) => s.collect_str(&format_args!("{id}.{meth}")),
+            IDLValue::Blob(b) => match blob {
+                BlobFormat::Base64 => {
+                    use base64::{engine::general_purpose::STANDARD, Engine};
+                    s.serialize_str(&STANDARD.encode(b))
+                }
+                BlobFormat::Hex => s.serialize_str(&hex::encode(b)),
+                BlobFormat::Array => s.collect_seq(b),
+            },
+            IDLValue::Vec(vs) => s.collect_seq(vs.iter().map(|v| Json(v, blob))),
+            // keys are sorted to keep the output stable
+            IDLValue::Record(fs) => s.collect_map(
+                fs.iter()
+                    .map(|f| (label_to_key(&f.id), Json(&f.val, blob)))
+                    .collect::<BTreeMap<_, _>>(),
+            ),
+            IDLValue::Variant(candid::types::value::VariantValue(f, _)) => {
+                s.collect_map([(label_to_key(&f.id), Json(&f.val, blob))])
+            }
+        }
+    }
+}
+
+#[test]
+fn test_from_json() -> Result<()> {
+    let v = from_json(r#"{"n": 123456789012345678901234567890, "f": 1e3, "a": [-1, "x"]}"#)?;
+    let expected = candid_parser::parse_idl_value(
+        r#"record { n = 123456789012345678901234567890; f = 1000.0 : float64; a = vec { -1; "x" } }"#,
+    )?;
+    assert_eq!(v, expected);
+    assert_eq!(
+        to_json(&v, BlobFormat::Base64)?,
+        r#"{"a":[-1,"x"],"f":1000.0,"n":123456789012345678901234567890}"#
+    );
+    let v = candid_parser::parse_idl_value(
+        "record { a = 18446744073709551616 : nat; b = -18446744073709551617 : int }",
+    )?;
+    assert_eq!(
+        to_json(&v, BlobFormat::Base64)?,
+        r#"{"a":18446744073709551616,"b":-18446744073709551617}"#
+    );
+    assert_eq!(
+        from_json(" [ 1e3 , {} , null ] ")?,
+        from_json("[1000.0,{},null]")?
+    );
+    Ok(())
+}
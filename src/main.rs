@@ -9,6 +9,11 @@ mod error;
 mod exp;
 mod grammar;
 mod helper;
+mod icrc;
+mod json;
+mod management;
+mod msgpack;
+mod nns;
 mod offline;
 mod profiling;
 mod selector;
@@ -30,14 +35,22 @@ where
 }
 
 fn repl(opts: Opts) -> anyhow::Result<()> {
-    let mut replica = opts.replica.unwrap_or_else(|| "local".to_string());
+    // offline mode signs messages for mainnet, unless a replica is given explicitly
+    let default_replica = if opts.offline { "ic" } else { "local" };
+    let replica = opts.replica.unwrap_or_else(|| default_replica.to_string());
+    let url = match replica.as_str() {
+        "local" => "http://localhost:4943/",
+        "ic" => "https://icp0.io",
+        url => url,
+    };
     let offline = if opts.offline {
-        replica = "ic".to_string();
         let send_url = opts
             .url
             .unwrap_or_else(|| "https://qhmh2-niaaa-aaaab-qadta-cai.raw.icp0.io/?msg=".to_string());
         Some(match opts.format.as_deref() {
             None | Some("json") => OfflineOutput::Json,
+            Some("cbor") => OfflineOutput::Cbor,
+            Some("curl") => OfflineOutput::Curl(url.to_string()),
             Some("ascii") => OfflineOutput::Ascii(send_url),
             Some("png") => OfflineOutput::Png(send_url),
             Some("png_no_url") => OfflineOutput::PngNoUrl,
@@ -47,11 +60,6 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
     } else {
         None
     };
-    let url = match replica.as_str() {
-        "local" => "http://localhost:4943/",
-        "ic" => "https://icp0.io",
-        url => url,
-    };
     println!("Ping {url}...");
     let agent = Agent::builder()
         .with_url(url)
@@ -66,10 +74,8 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
         .build();
     let h = MyHelper::new(agent, url.to_string(), offline, opts.verbose);
     if let Some(file) = opts.send {
-        use crate::offline::{send_messages, Messages};
-        let json = std::fs::read_to_string(file)?;
-        let msgs = serde_json::from_str::<Messages>(&json)?;
-        send_messages(&h, &msgs)?;
+        let blob = std::fs::read(file)?;
+        crate::offline::send_blob(&h, &blob)?;
         return Ok(());
     }
     let mut rl = rustyline::Editor::with_config(config)?;
@@ -77,13 +83,18 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
     let _ = rl.load_history("./.history");
     if let Some(file) = opts.config {
         let config = std::fs::read_to_string(file)?;
-        rl.helper_mut().unwrap().config = config.parse::<candid_parser::configs::Configs>()?;
+        rl.helper_mut().unwrap().set_config(&config)?;
     }
 
     let enter_repl = opts.script.is_none() || opts.interactive;
     if let Some(file) = opts.script {
-        let cmd = Command::Load(exp::Exp::Text(file));
+        let cmd = Command::Load(exp::Exp::Text(file), None);
         let helper = rl.helper_mut().unwrap();
+        let argv = opts.extra_args.iter().cloned().map(candid::IDLValue::Text);
+        helper
+            .env
+            .0
+            .insert("_argv".to_string(), candid::IDLValue::Vec(argv.collect()));
         cmd.run(helper)?;
         if helper.func_env.0.contains_key("__main") {
             let mut args = Vec::new();
@@ -127,6 +138,13 @@ fn repl(opts: Opts) -> anyhow::Result<()> {
             helper.dump_ingress()?;
         }
     }
+    let stats = rl.helper().unwrap().test_stats.borrow();
+    let exit_code = stats
+        .exit_code
+        .or((!enter_repl && !stats.failed.is_empty()).then_some(1));
+    if let Some(code) = exit_code {
+        std::process::exit(code);
+    }
     Ok(())
 }
 
@@ -136,10 +154,10 @@ struct Opts {
     #[clap(short, long)]
     /// Specifies replica URL, possible values: local, ic, URL
     replica: Option<String>,
-    #[clap(short, long, conflicts_with("replica"))]
-    /// Offline mode to be run in air-gap machines. All signed messages will be stored in messages.json
+    #[clap(short, long)]
+    /// Offline mode to be run in air-gap machines. All signed messages will be stored in messages.json, or messages.cbor in cbor format
     offline: bool,
-    #[clap(short, long, requires("offline"), value_parser = ["ascii", "json", "png", "ascii_no_url", "png_no_url"])]
+    #[clap(short, long, requires("offline"), value_parser = ["ascii", "json", "cbor", "curl", "png", "ascii_no_url", "png_no_url"])]
     /// Offline output format
     format: Option<String>,
     #[clap(short, long, requires("offline"))]
@@ -0,0 +1,61 @@
+use crate::utils::{field, get_field, record};
+use anyhow::{anyhow, Result};
+use candid::{
+    types::value::{IDLValue, VariantValue},
+    IDLArgs, Principal,
+};
+
+pub const MANAGEMENT_CANISTER_ID: &str = "aaaaa-aa";
+
+/// Build the argument of `create_canister` or `provisional_create_canister_with_cycles`.
+pub fn create_canister_arg(settings: Option<IDLValue>) -> IDLValue {
+    let settings = match settings {
+        Some(v) => IDLValue::Opt(Box::new(v)),
+        None => IDLValue::None,
+    };
+    record(vec![field("settings", settings)])
+}
+
+/// Extract the canister id from the result of `create_canister`.
+pub fn created_canister_id(res: &IDLValue) -> Result<IDLValue> {
+    match res {
+        IDLValue::Record(fs) => get_field(fs, "canister_id").cloned(),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("unexpected create_canister response: {res}"))
+}
+
+/// Build the argument of `install_code`. `mode` is one of `install`, `reinstall` or `upgrade`.
+/// Without `arg`, the canister is installed with empty candid arguments.
+pub fn install_code_arg(
+    canister_id: Principal,
+    wasm: Vec<u8>,
+    mode: &str,
+    arg: Option<Vec<u8>>,
+) -> Result<IDLValue> {
+    let val = match mode {
+        "install" | "reinstall" => IDLValue::Null,
+        "upgrade" => IDLValue::None,
+        _ => {
+            return Err(anyhow!(
+                "install mode expects install, reinstall or upgrade"
+            ))
+        }
+    };
+    let mode = IDLValue::Variant(VariantValue(Box::new(field(mode, val)), 0));
+    let arg = match arg {
+        Some(arg) => arg,
+        None => IDLArgs::new(&[]).to_bytes()?,
+    };
+    Ok(record(vec![
+        field("mode", mode),
+        field("canister_id", IDLValue::Principal(canister_id)),
+        field("wasm_module", IDLValue::Blob(wasm)),
+        field("arg", IDLValue::Blob(arg)),
+    ]))
+}
+
+/// Build the `record { canister_id }` argument shared by most management canister methods.
+pub fn canister_id_arg(canister_id: Principal) -> IDLValue {
+    record(vec![field("canister_id", IDLValue::Principal(canister_id))])
+}
@@ -0,0 +1,289 @@
+use anyhow::{anyhow, Result};
+use candid::types::value::{IDLField, IDLValue, VariantValue};
+use candid::types::Label;
+
+pub fn encode(v: &IDLValue) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    encode_value(v, &mut buf)?;
+    Ok(buf)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<IDLValue> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let v = reader.value()?;
+    if reader.pos != bytes.len() {
+        return Err(anyhow!("trailing bytes in msgpack data"));
+    }
+    Ok(v)
+}
+
+fn encode_value(v: &IDLValue, buf: &mut Vec<u8>) -> Result<()> {
+    match v {
+        IDLValue::Null | IDLValue::None | IDLValue::Reserved => buf.push(0xc0),
+        IDLValue::Bool(false) => buf.push(0xc2),
+        IDLValue::Bool(true) => buf.push(0xc3),
+        IDLValue::Opt(v) => encode_value(v, buf)?,
+        IDLValue::Nat8(n) => encode_uint(*n as u64, buf),
+        IDLValue::Nat16(n) => encode_uint(*n as u64, buf),
+        IDLValue::Nat32(n) => encode_uint(*n as u64, buf),
+        IDLValue::Nat64(n) => encode_uint(*n, buf),
+        IDLValue::Int8(n) => encode_int(*n as i64, buf),
+        IDLValue::Int16(n) => encode_int(*n as i64, buf),
+        IDLValue::Int32(n) => encode_int(*n as i64, buf),
+        IDLValue::Int64(n) => encode_int(*n, buf),
+        IDLValue::Nat(n) => encode_number(&n.to_string(), buf)?,
+        IDLValue::Int(n) => encode_number(&n.to_string(), buf)?,
+        IDLValue::Number(n) => encode_number(n, buf)?,
+        IDLValue::Float32(f) => {
+            buf.push(0xca);
+            buf.extend_from_slice(&f.to_be_bytes());
+        }
+        IDLValue::Float64(f) => {
+            buf.push(0xcb);
+            buf.extend_from_slice(&f.to_be_bytes());
+        }
+        IDLValue::Text(s) => encode_str(s, buf),
+        IDLValue::Principal(id) | IDLValue::Service(id) => encode_str(&id.to_text(), buf),
+        IDLValue::Func(id, meth) => encode_str(&format!("{id}.{meth}"), buf),
+        IDLValue::Blob(b) => {
+            encode_len(b.len(), [None, Some(0xc4), Some(0xc5), Some(0xc6)], 0, buf);
+            buf.extend_from_slice(b);
+        }
+        IDLValue::Vec(vs) => {
+            encode_len(
+                vs.len(),
+                [Some(0x0f), None, Some(0xdc), Some(0xdd)],
+                0x90,
+                buf,
+            );
+            for v in vs {
+                encode_value(v, buf)?;
+            }
+        }
+        IDLValue::Record(fs) => {
+            encode_len(
+                fs.len(),
+                [Some(0x0f), None, Some(0xde), Some(0xdf)],
+                0x80,
+                buf,
+            );
+            for f in fs {
+                encode_str(&label_to_string(&f.id), buf);
+                encode_value(&f.val, buf)?;
+            }
+        }
+        IDLValue::Variant(VariantValue(f, _)) => {
+            buf.push(0x81);
+            encode_str(&label_to_string(&f.id), buf);
+            encode_value(&f.val, buf)?;
+        }
+    }
+    Ok(())
+}
+
+fn label_to_string(id: &Label) -> String {
+    match id {
+        Label::Named(name) => name.clone(),
+        Label::Id(n) | Label::Unnamed(n) => n.to_string(),
+    }
+}
+
+// formats: [fixed format mask, 8-bit, 16-bit, 32-bit]
+fn encode_len(len: usize, formats: [Option<u8>; 4], fixed: u8, buf: &mut Vec<u8>) {
+    match formats {
+        [Some(mask), ..] if len <= mask as usize => buf.push(fixed | len as u8),
+        [_, Some(tag), ..] if len <= u8::MAX as usize => buf.extend_from_slice(&[tag, len as u8]),
+        [_, _, Some(tag), _] if len <= u16::MAX as usize => {
+            buf.push(tag);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        [.., Some(tag)] => {
+            buf.push(tag);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn encode_str(s: &str, buf: &mut Vec<u8>) {
+    encode_len(
+        s.len(),
+        [Some(0x1f), Some(0xd9), Some(0xda), Some(0xdb)],
+        0xa0,
+        buf,
+    );
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn encode_uint(n: u64, buf: &mut Vec<u8>) {
+    if n <= 0x7f {
+        buf.push(n as u8);
+    } else if n <= u8::MAX as u64 {
+        buf.extend_from_slice(&[0xcc, n as u8]);
+    } else if n <= u16::MAX as u64 {
+        buf.push(0xcd);
+        buf.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX as u64 {
+        buf.push(0xce);
+        buf.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        buf.push(0xcf);
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn encode_int(n: i64, buf: &mut Vec<u8>) {
+    if n >= 0 {
+        encode_uint(n as u64, buf);
+    } else if n >= -32 {
+        buf.push(n as u8);
+    } else if n >= i8::MIN as i64 {
+        buf.extend_from_slice(&[0xd0, n as u8]);
+    } else if n >= i16::MIN as i64 {
+        buf.push(0xd1);
+        buf.extend_from_slice(&(n as i16).to_be_bytes());
+    } else if n >= i32::MIN as i64 {
+        buf.push(0xd2);
+        buf.extend_from_slice(&(n as i32).to_be_bytes());
+    } else {
+        buf.push(0xd3);
+        buf.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+fn encode_number(n: &str, buf: &mut Vec<u8>) -> Result<()> {
+    let n = n.replace('_', "");
+    if let Ok(n) = n.parse::<u64>() {
+        encode_uint(n, buf);
+    } else if let Ok(n) = n.parse::<i64>() {
+        encode_int(n, buf);
+    } else {
+        return Err(anyhow!("{n} cannot be represented in msgpack"));
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow!("unexpected end of msgpack data"))?;
+        let res = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(res)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+    fn len(&mut self, size: usize) -> Result<usize> {
+        Ok(match size {
+            1 => self.array::<1>()?[0] as usize,
+            2 => u16::from_be_bytes(self.array()?) as usize,
+            _ => u32::from_be_bytes(self.array()?) as usize,
+        })
+    }
+    fn value(&mut self) -> Result<IDLValue> {
+        let tag = self.array::<1>()?[0];
+        Ok(match tag {
+            0x00..=0x7f => IDLValue::Number(tag.to_string()),
+            0x80..=0x8f => self.map((tag & 0x0f) as usize)?,
+            0x90..=0x9f => self.vec((tag & 0x0f) as usize)?,
+            0xa0..=0xbf => self.str((tag & 0x1f) as usize)?,
+            0xc0 => IDLValue::Null,
+            0xc2 => IDLValue::Bool(false),
+            0xc3 => IDLValue::Bool(true),
+            0xc4..=0xc6 => {
+                let len = self.len(1 << (tag - 0xc4))?;
+                IDLValue::Blob(self.take(len)?.to_vec())
+            }
+            0xca => IDLValue::Float64(f32::from_be_bytes(self.array()?) as f64),
+            0xcb => IDLValue::Float64(f64::from_be_bytes(self.array()?)),
+            0xcc => IDLValue::Number(self.array::<1>()?[0].to_string()),
+            0xcd => IDLValue::Number(u16::from_be_bytes(self.array()?).to_string()),
+            0xce => IDLValue::Number(u32::from_be_bytes(self.array()?).to_string()),
+            0xcf => IDLValue::Number(u64::from_be_bytes(self.array()?).to_string()),
+            0xd0 => IDLValue::Number(i8::from_be_bytes(self.array()?).to_string()),
+            0xd1 => IDLValue::Number(i16::from_be_bytes(self.array()?).to_string()),
+            0xd2 => IDLValue::Number(i32::from_be_bytes(self.array()?).to_string()),
+            0xd3 => IDLValue::Number(i64::from_be_bytes(self.array()?).to_string()),
+            0xd9..=0xdb => {
+                let len = self.len(1 << (tag - 0xd9))?;
+                self.str(len)?
+            }
+            0xdc | 0xdd => {
+                let len = self.len(2 << (tag - 0xdc))?;
+                self.vec(len)?
+            }
+            0xde | 0xdf => {
+                let len = self.len(2 << (tag - 0xde))?;
+                self.map(len)?
+            }
+            0xe0..=0xff => IDLValue::Number((tag as i8).to_string()),
+            _ => return Err(anyhow!("unsupported msgpack type {tag:#x}")),
+        })
+    }
+    fn str(&mut self, len: usize) -> Result<IDLValue> {
+        Ok(IDLValue::Text(String::from_utf8(self.take(len)?.to_vec())?))
+    }
+    fn vec(&mut self, len: usize) -> Result<IDLValue> {
+        let mut res = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0..len {
+            res.push(self.value()?);
+        }
+        Ok(IDLValue::Vec(res))
+    }
+    fn map(&mut self, len: usize) -> Result<IDLValue> {
+        let mut fs = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0..len {
+            let id = match self.value()? {
+                // numeric keys come from tuple or unnamed fields in `encode`
+                IDLValue::Text(key) => match key.parse::<u32>() {
+                    Ok(n) => Label::Id(n),
+                    Err(_) => Label::Named(key),
+                },
+                IDLValue::Number(n) => Label::Id(n.parse::<u32>()?),
+                _ => return Err(anyhow!("msgpack map keys must be string or nat32")),
+            };
+            let val = self.value()?;
+            fs.push(IDLField { id, val });
+        }
+        fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+        candid::utils::check_unique(fs.iter().map(|f| &f.id))?;
+        Ok(IDLValue::Record(fs))
+    }
+}
+
+#[test]
+fn test_msgpack() {
+    let parse = |s: &str| candid_parser::parse_idl_value(s).unwrap();
+    let roundtrip = |v: &IDLValue| decode(&encode(v).unwrap()).unwrap();
+    let v = parse(
+        r#"record { a = record { b = vec { 1; -300; "x" }; c = null }; 42 = record { true; 1.5 } }"#,
+    );
+    assert_eq!(roundtrip(&v), v);
+    let max = IDLValue::Nat64(u64::MAX);
+    assert_eq!(encode(&max).unwrap()[0], 0xcf);
+    assert_eq!(
+        roundtrip(&max),
+        IDLValue::Number("18446744073709551615".to_string())
+    );
+    // nat values beyond u64 have no msgpack representation
+    assert!(encode(&parse("18446744073709551616 : nat")).is_err());
+    assert!(encode(&parse("-9223372036854775809 : int")).is_err());
+    // blob becomes msgpack bin, while vec nat8 becomes an array of ints
+    let blob = IDLValue::Blob(vec![1, 2]);
+    let bytes = IDLValue::Vec(vec![IDLValue::Nat8(1), IDLValue::Nat8(2)]);
+    assert_eq!(encode(&blob).unwrap(), vec![0xc4, 2, 1, 2]);
+    assert_eq!(encode(&bytes).unwrap(), vec![0x92, 1, 2]);
+    assert_eq!(roundtrip(&blob), blob);
+    assert_eq!(roundtrip(&bytes), parse("vec { 1; 2 }"));
+    assert!(decode(&[0x92, 1]).is_err());
+    assert!(decode(&[0x92, 1, 2, 3]).is_err());
+}
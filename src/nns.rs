@@ -0,0 +1,189 @@
+use crate::utils::{cast_type, field, get_field, record};
+use anyhow::{anyhow, Result};
+use candid::{
+    types::value::{IDLField, IDLValue, VariantValue},
+    types::{Label, TypeInner},
+};
+
+pub const GOVERNANCE_CANISTER_ID: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+
+fn neuron_id(id: IDLValue) -> IDLValue {
+    record(vec![field("id", id)])
+}
+
+fn variant(tag: &str, val: IDLValue) -> IDLValue {
+    IDLValue::Variant(VariantValue(Box::new(field(tag, val)), 0))
+}
+
+fn text(fs: &[IDLField], name: &str) -> Result<Option<String>> {
+    let v = match get_field(fs, name) {
+        Some(IDLValue::Opt(v)) => Some(v.as_ref()),
+        Some(IDLValue::None) | None => None,
+        v => v,
+    };
+    match v {
+        None => Ok(None),
+        Some(IDLValue::Text(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(anyhow!("{name} expects a text")),
+    }
+}
+
+/// Build the `ManageNeuron` argument for `MakeProposal` from
+/// `record { neuron_id; title; summary; url; action }`. `url` is optional.
+pub fn make_proposal_arg(fs: &[IDLField]) -> Result<IDLValue> {
+    let id = get_field(fs, "neuron_id").ok_or_else(|| anyhow!("neuron_id is required"))?;
+    let id = cast_type(id.clone(), &TypeInner::Nat64.into())
+        .map_err(|_| anyhow!("neuron_id expects a nat64"))?;
+    let title = text(fs, "title")?.ok_or_else(|| anyhow!("title is required"))?;
+    let summary = text(fs, "summary")?.ok_or_else(|| anyhow!("summary is required"))?;
+    let url = text(fs, "url")?.unwrap_or_default();
+    let action = match get_field(fs, "action") {
+        Some(v @ IDLValue::Variant(_)) => v.clone(),
+        _ => {
+            return Err(anyhow!(
+                "action expects a variant of the governance Action type"
+            ))
+        }
+    };
+    let proposal = record(vec![
+        field("title", IDLValue::Opt(Box::new(IDLValue::Text(title)))),
+        field("summary", IDLValue::Text(summary)),
+        field("url", IDLValue::Text(url)),
+        field("action", IDLValue::Opt(Box::new(action))),
+    ]);
+    Ok(record(vec![
+        field("id", IDLValue::Opt(Box::new(neuron_id(id)))),
+        field(
+            "command",
+            IDLValue::Opt(Box::new(variant("MakeProposal", proposal))),
+        ),
+    ]))
+}
+
+fn governance_error(v: &IDLValue) -> anyhow::Error {
+    match v {
+        IDLValue::Record(fs) => match get_field(fs, "error_message") {
+            Some(IDLValue::Text(msg)) => anyhow!("governance error: {msg}"),
+            _ => anyhow!("governance error: {v}"),
+        },
+        _ => anyhow!("governance error: {v}"),
+    }
+}
+
+/// Extract the proposal id from a `ManageNeuronResponse`.
+pub fn proposal_id(res: &IDLValue) -> Result<IDLValue> {
+    let command = match res {
+        IDLValue::Record(fs) => get_field(fs, "command"),
+        _ => None,
+    };
+    let Some(IDLValue::Opt(command)) = command else {
+        return Err(anyhow!("unexpected manage_neuron response: {res}"));
+    };
+    let IDLValue::Variant(VariantValue(f, _)) = command.as_ref() else {
+        return Err(anyhow!("unexpected manage_neuron response: {res}"));
+    };
+    if f.id == Label::Named("Error".to_string()) {
+        return Err(governance_error(&f.val));
+    }
+    if f.id != Label::Named("MakeProposal".to_string()) {
+        return Err(anyhow!("unexpected manage_neuron response: {res}"));
+    }
+    match &f.val {
+        IDLValue::Record(fs) => match get_field(fs, "proposal_id") {
+            Some(IDLValue::Opt(id)) => match id.as_ref() {
+                IDLValue::Record(fs) => get_field(fs, "id").cloned(),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("manage_neuron returned no proposal id: {res}"))
+}
+
+#[test]
+fn test_proposal_id() -> Result<()> {
+    let parse = |s: &str| candid_parser::parse_idl_value(s).unwrap();
+    let ok = parse("record { command = opt variant { MakeProposal = record { message = null; proposal_id = opt record { id = 7 : nat64 } } } }");
+    assert_eq!(proposal_id(&ok)?, IDLValue::Nat64(7));
+    let err = parse(
+        r#"record { command = opt variant { Error = record { error_type = 3 : int32; error_message = "not authorized" } } }"#,
+    );
+    assert_eq!(
+        proposal_id(&err).unwrap_err().to_string(),
+        "governance error: not authorized"
+    );
+    Ok(())
+}
+
+const PROPOSAL_STATUS: [&str; 6] = [
+    "Unspecified",
+    "Open",
+    "Rejected",
+    "Adopted",
+    "Executed",
+    "Failed",
+];
+
+const REWARD_STATUS: [&str; 5] = [
+    "Unspecified",
+    "AcceptVotes",
+    "ReadyToSettle",
+    "Settled",
+    "Ineligible",
+];
+
+/// Unwrap the `opt ProposalInfo` returned by `get_proposal_info`, and replace the numeric
+/// `status` and `reward_status` fields with their names.
+pub fn proposal_info(id: &IDLValue, res: IDLValue) -> Result<IDLValue> {
+    let info = match res {
+        IDLValue::Opt(info) => *info,
+        IDLValue::None | IDLValue::Null => return Err(anyhow!("proposal {id} not found")),
+        res => return Err(anyhow!("unexpected get_proposal_info response: {res}")),
+    };
+    let IDLValue::Record(fs) = info else {
+        return Err(anyhow!("unexpected get_proposal_info response: {info}"));
+    };
+    let fs = fs
+        .into_iter()
+        .map(|f| {
+            let names: &[&str] = if f.id == Label::Named("status".to_string()) {
+                &PROPOSAL_STATUS
+            } else if f.id == Label::Named("reward_status".to_string()) {
+                &REWARD_STATUS
+            } else {
+                return f;
+            };
+            match f.val {
+                IDLValue::Int32(n) if (0..names.len() as i32).contains(&n) => IDLField {
+                    id: f.id,
+                    val: IDLValue::Text(names[n as usize].to_string()),
+                },
+                _ => f,
+            }
+        })
+        .collect();
+    Ok(IDLValue::Record(fs))
+}
+
+/// Flatten a `ListSnsCanistersResponse` into `record { root; governance; ledger; swap; index; dapps; archives }`,
+/// where missing canisters are omitted.
+pub fn sns_canisters(res: IDLValue) -> Result<IDLValue> {
+    let IDLValue::Record(fs) = res else {
+        return Err(anyhow!("unexpected list_sns_canisters response: {res}"));
+    };
+    let mut res = Vec::new();
+    for role in ["root", "governance", "ledger", "swap", "index"] {
+        match get_field(&fs, role) {
+            Some(IDLValue::Opt(id)) => res.push(field(role, *id.clone())),
+            Some(IDLValue::Principal(id)) => res.push(field(role, IDLValue::Principal(*id))),
+            _ => (),
+        }
+    }
+    for role in ["dapps", "archives"] {
+        if let Some(ids) = get_field(&fs, role) {
+            res.push(field(role, ids.clone()));
+        }
+    }
+    Ok(record(res))
+}
@@ -1,5 +1,5 @@
 use crate::helper::{MyHelper, OfflineOutput};
-use crate::utils::args_to_value;
+use crate::utils::{args_to_value, runtime};
 use anyhow::{anyhow, Context, Result};
 use candid::Principal;
 use candid::{types::Function, IDLArgs, TypeEnv};
@@ -11,6 +11,8 @@ pub struct Ingress {
     pub call_type: String,
     pub request_id: Option<String>,
     pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_id: Option<Principal>,
 }
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RequestStatus {
@@ -27,6 +29,8 @@ pub struct IngressWithStatus {
 pub struct Messages(Vec<IngressWithStatus>);
 
 static mut PNG_COUNTER: u32 = 0;
+/// Maximum number of base64 characters in a single QR code when a message has to be split.
+const QR_CHUNK_SIZE: usize = 2000;
 
 impl Ingress {
     pub fn parse(&self) -> Result<(Principal, Principal, String, Vec<u8>)> {
@@ -60,10 +64,13 @@ impl Ingress {
 }
 
 #[allow(static_mut_refs)]
-pub fn output_message(json: String, format: &OfflineOutput) -> Result<()> {
+pub fn output_message(message: &IngressWithStatus, format: &OfflineOutput) -> Result<()> {
     match format {
-        OfflineOutput::Json => println!("{json}"),
+        OfflineOutput::Json => println!("{}", serde_json::to_string(message)?),
+        OfflineOutput::Cbor => println!("{}", hex::encode(serde_cbor::to_vec(message)?)),
+        OfflineOutput::Curl(url) => println!("{}", curl_command(url, message)?),
         _ => {
+            let json = serde_json::to_string(message)?;
             use base64::{
                 engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD},
                 Engine,
@@ -82,48 +89,119 @@ pub fn output_message(json: String, format: &OfflineOutput) -> Result<()> {
             };
             let base64 = engine.encode(zipped);
             eprintln!("base64 length: {}", base64.len());
-            let msg = match format {
-                OfflineOutput::Ascii(url) | OfflineOutput::Png(url) => url.to_owned() + &base64,
-                _ => base64,
+            let url = match format {
+                OfflineOutput::Ascii(url) | OfflineOutput::Png(url) => url.as_str(),
+                _ => "",
             };
-            let code = QrCode::new(&msg).or_else(|e| {
-                // Try to lower the error correction level to make the data fit.
-                if let QrError::DataTooLong = e {
-                    QrCode::with_error_correction_level(&msg, EcLevel::L)
-                } else {
-                    Err(e)
-                }
-            })?;
-            match format {
-                OfflineOutput::Ascii(_) | OfflineOutput::AsciiNoUrl => {
-                    let img = code.render::<unicode::Dense1x2>().build();
-                    println!("{img}");
+            let qr_code = |msg: &str| {
+                QrCode::new(msg).or_else(|e| {
+                    // Try to lower the error correction level to make the data fit.
+                    if let QrError::DataTooLong = e {
+                        QrCode::with_error_correction_level(msg, EcLevel::L)
+                    } else {
+                        Err(e)
+                    }
+                })
+            };
+            let codes = match qr_code(&(url.to_owned() + &base64)) {
+                Ok(code) => vec![code],
+                Err(QrError::DataTooLong) => {
+                    // Split the message into multiple QR codes, each prefixed with `i/n:`.
+                    let chunks: Vec<_> = base64.as_bytes().chunks(QR_CHUNK_SIZE).collect();
+                    let n = chunks.len();
+                    eprintln!("message too long for a single QR code, split into {n} chunks");
+                    chunks
+                        .into_iter()
+                        .enumerate()
+                        .map(|(i, chunk)| {
+                            let chunk = std::str::from_utf8(chunk).unwrap();
+                            qr_code(&format!("{url}{}/{n}:{chunk}", i + 1))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?
                 }
-                OfflineOutput::Png(_) | OfflineOutput::PngNoUrl => {
-                    let img = code.render::<image::Luma<u8>>().build();
-                    let filename = unsafe {
-                        PNG_COUNTER += 1;
-                        format!("msg{PNG_COUNTER}.png")
-                    };
-                    img.save(&filename)?;
-                    println!("QR code saved to {filename}");
+                Err(e) => return Err(e.into()),
+            };
+            for code in codes {
+                match format {
+                    OfflineOutput::Ascii(_) | OfflineOutput::AsciiNoUrl => {
+                        let img = code.render::<unicode::Dense1x2>().build();
+                        println!("{img}");
+                    }
+                    OfflineOutput::Png(_) | OfflineOutput::PngNoUrl => {
+                        let img = code.render::<image::Luma<u8>>().build();
+                        let filename = unsafe {
+                            PNG_COUNTER += 1;
+                            format!("msg{PNG_COUNTER}.png")
+                        };
+                        img.save(&filename)?;
+                        println!("QR code saved to {filename}");
+                    }
+                    _ => unreachable!(),
                 }
-                _ => unreachable!(),
             }
         }
     };
     Ok(())
 }
-pub fn dump_ingress(msgs: &[IngressWithStatus]) -> Result<()> {
+/// Render a signed message as `curl` commands that post the CBOR content to the replica.
+/// For update calls, a second command reads the request status.
+fn curl_command(url: &str, message: &IngressWithStatus) -> Result<String> {
+    let url = url.trim_end_matches('/');
+    let ingress = &message.ingress;
+    let (kind, endpoint) = match ingress.call_type.as_str() {
+        "query" => ("canister", "query"),
+        "update" => ("canister", "call"),
+        "read_state" => ("canister", "read_state"),
+        "read_subnet_state" => ("subnet", "read_state"),
+        t => return Err(anyhow!("Unknown call type {t}")),
+    };
+    let id = match (&ingress.effective_id, &message.request_status) {
+        (Some(id), _) => *id,
+        (None, Some(status)) => status.canister_id,
+        (None, None) => ingress.parse()?.1,
+    };
+    let curl = |endpoint: &str, content: &str| {
+        format!("echo {content} | xxd -r -p | curl -X POST -H 'Content-Type: application/cbor' --data-binary @- {url}/api/v2/{kind}/{id}/{endpoint}")
+    };
+    let mut res = curl(endpoint, &ingress.content);
+    if let Some(status) = &message.request_status {
+        res.push_str("\n# request status\n");
+        res.push_str(&curl("read_state", &status.content));
+    }
+    Ok(res)
+}
+pub fn dump_ingress(msgs: &[IngressWithStatus], format: &OfflineOutput) -> Result<()> {
     use std::fs::File;
     use std::io::Write;
     let msgs = Messages(msgs.to_vec());
-    let json = serde_json::to_string(&msgs)?;
-    let mut file = File::create("messages.json")?;
-    file.write_all(json.as_bytes())?;
+    let (file, bytes) = match format {
+        OfflineOutput::Cbor => ("messages.cbor", serde_cbor::to_vec(&msgs)?),
+        _ => ("messages.json", serde_json::to_vec(&msgs)?),
+    };
+    let mut file = File::create(file)?;
+    file.write_all(&bytes)?;
     Ok(())
 }
 
+/// Send signed messages stored in either JSON or CBOR format. The format is sniffed from the
+/// first byte, as JSON messages always start with `{` or `[`.
+pub fn send_blob(helper: &MyHelper, blob: &[u8]) -> Result<IDLArgs> {
+    let first = blob.iter().find(|b| !b.is_ascii_whitespace());
+    match first {
+        Some(b'{') => send(helper, &serde_json::from_slice(blob)?),
+        Some(b'[') => send_messages(helper, &serde_json::from_slice(blob)?),
+        Some(_) => match serde_cbor::from_slice::<Messages>(blob) {
+            Ok(msgs) => send_messages(helper, &msgs),
+            Err(_) => {
+                let msg =
+                    serde_cbor::from_slice(blob).context("not a valid json or cbor message")?;
+                send(helper, &msg)
+            }
+        },
+        None => Err(anyhow!("empty message")),
+    }
+}
+
 pub fn send_messages(helper: &MyHelper, msgs: &Messages) -> Result<IDLArgs> {
     let len = msgs.0.len();
     let mut res = Vec::with_capacity(len);
@@ -137,10 +215,17 @@ pub fn send_messages(helper: &MyHelper, msgs: &Messages) -> Result<IDLArgs> {
 }
 pub fn send(helper: &MyHelper, msg: &IngressWithStatus) -> Result<IDLArgs> {
     let message = &msg.ingress;
+    if message.call_type.starts_with("read_") {
+        return Err(anyhow!(
+            "Sending signed {} messages is not supported",
+            message.call_type
+        ));
+    }
     let (sender, canister_id, method_name, bytes) = message.parse()?;
     let meth = crate::exp::Method {
         canister: canister_id.to_string(),
         method: method_name.clone(),
+        ..Default::default()
     };
     let opt_func = meth.get_info(helper, false)?.signature;
     let args = if let Some((env, func)) = &opt_func {
@@ -158,43 +243,44 @@ pub fn send(helper: &MyHelper, msg: &IngressWithStatus) -> Result<IDLArgs> {
     }
     send_internal(&helper.agent, canister_id, msg, &opt_func)
 }
-#[tokio::main]
-async fn send_internal(
+fn send_internal(
     agent: &Agent,
     canister_id: Principal,
     message: &IngressWithStatus,
     opt_func: &Option<(TypeEnv, Function)>,
 ) -> Result<IDLArgs> {
-    let content = hex::decode(&message.ingress.content)?;
-    let response = match message.ingress.call_type.as_str() {
-        "query" => agent.query_signed(canister_id, content).await?,
-        "update" => {
-            let call_response = agent.update_signed(canister_id, content).await?;
-            match call_response {
-                CallResponse::Response(blob) => blob,
-                CallResponse::Poll(request_id) => {
-                    println!("Request ID: 0x{}", String::from(request_id));
-                    let status = message
-                        .request_status
-                        .as_ref()
-                        .ok_or_else(|| anyhow!("Cannot get request status for update call"))?;
-                    if !(status.canister_id == canister_id
-                        && status.request_id == String::from(request_id))
-                    {
-                        return Err(anyhow!("request_id doesn't match, cannot request status"));
+    runtime().block_on(async move {
+        let content = hex::decode(&message.ingress.content)?;
+        let response = match message.ingress.call_type.as_str() {
+            "query" => agent.query_signed(canister_id, content).await?,
+            "update" => {
+                let call_response = agent.update_signed(canister_id, content).await?;
+                match call_response {
+                    CallResponse::Response(blob) => blob,
+                    CallResponse::Poll(request_id) => {
+                        println!("Request ID: 0x{}", String::from(request_id));
+                        let status = message
+                            .request_status
+                            .as_ref()
+                            .ok_or_else(|| anyhow!("Cannot get request status for update call"))?;
+                        if !(status.canister_id == canister_id
+                            && status.request_id == String::from(request_id))
+                        {
+                            return Err(anyhow!("request_id doesn't match, cannot request status"));
+                        }
+                        let status = hex::decode(&status.content)?;
+                        agent.wait_signed(&request_id, canister_id, status).await?.0
                     }
-                    let status = hex::decode(&status.content)?;
-                    agent.wait_signed(&request_id, canister_id, status).await?.0
                 }
             }
-        }
-        _ => unreachable!(),
-    };
-    let res = if let Some((env, func)) = &opt_func {
-        IDLArgs::from_bytes_with_types(&response, env, &func.rets)?
-    } else {
-        IDLArgs::from_bytes(&response)?
-    };
-    println!("{res}");
-    Ok(res)
+            _ => unreachable!(),
+        };
+        let res = if let Some((env, func)) = &opt_func {
+            IDLArgs::from_bytes_with_types(&response, env, &func.rets)?
+        } else {
+            IDLArgs::from_bytes(&response)?
+        };
+        println!("{res}");
+        Ok(res)
+    })
 }
@@ -1,24 +1,30 @@
 use crate::exp::MethodInfo;
 use crate::helper::MyHelper;
+use crate::utils::runtime;
 use anyhow::anyhow;
 use candid::{
     types::value::{IDLField, IDLValue},
-    types::Label,
+    types::{FuncMode, Label},
     Principal,
 };
 use ic_agent::Agent;
 use std::collections::BTreeMap;
 use std::path::PathBuf;
 
+/// Queries are only profiled with `config "profile_queries = true"`, as they have to be called as update calls.
+/// Composite queries cannot run in replicated mode, so they are never profiled.
 pub fn ok_to_profile<'a>(helper: &'a MyHelper, info: &'a MethodInfo) -> bool {
     helper.offline.is_none()
         && info.profiling.is_some()
-        && info.signature.as_ref().map(|s| s.1.is_query()) != Some(true)
+        && match info.signature.as_ref() {
+            Some((_, func)) if func.modes.contains(&FuncMode::CompositeQuery) => false,
+            Some((_, func)) if func.is_query() => helper.profile_queries,
+            _ => true,
+        }
 }
 
-#[tokio::main]
-pub async fn get_cycles(agent: &Agent, canister_id: &Principal) -> anyhow::Result<i64> {
-    get_cycles_inner(agent, canister_id).await
+pub fn get_cycles(agent: &Agent, canister_id: &Principal) -> anyhow::Result<i64> {
+    runtime().block_on(get_cycles_inner(agent, canister_id))
 }
 async fn get_cycles_inner(agent: &Agent, canister_id: &Principal) -> anyhow::Result<i64> {
     use candid::{Decode, Encode};
@@ -31,14 +37,34 @@ async fn get_cycles_inner(agent: &Agent, canister_id: &Principal) -> anyhow::Res
     Ok(Decode!(&bytes, i64)?)
 }
 
-#[tokio::main]
-pub async fn get_profiling(
+pub fn get_profiling(
     agent: &Agent,
     canister_id: &Principal,
     names: &BTreeMap<u16, String>,
     title: &str,
     filename: PathBuf,
 ) -> anyhow::Result<u64> {
+    runtime().block_on(async move {
+        let pairs = get_trace_inner(agent, canister_id).await?;
+        if !pairs.is_empty() {
+            match render_profiling(pairs, names, title, filename)? {
+                CostValue::Complete(cost) => Ok(cost),
+                CostValue::StartCost(start) => {
+                    let end = get_cycles_inner(agent, canister_id).await? as u64;
+                    Ok(end - start)
+                }
+            }
+        } else {
+            eprintln!("empty trace");
+            Ok(0)
+        }
+    })
+}
+
+async fn get_trace_inner(
+    agent: &Agent,
+    canister_id: &Principal,
+) -> anyhow::Result<Vec<(i32, i64)>> {
     use candid::{Decode, Encode};
     let mut idx = 0i32;
     let mut pairs = vec![];
@@ -63,17 +89,49 @@ pub async fn get_profiling(
     if cnt > 1 {
         eprintln!("large trace: {}MB", cnt * 2);
     }
-    if !pairs.is_empty() {
-        match render_profiling(pairs, names, title, filename)? {
-            CostValue::Complete(cost) => Ok(cost),
-            CostValue::StartCost(start) => {
-                let end = get_cycles_inner(agent, canister_id).await? as u64;
-                Ok(end - start)
-            }
+    Ok(pairs)
+}
+
+/// Summarize the trace of the last update call as `record { func_name = instructions }`,
+/// where the instructions of a function include its callees. Recursive calls are counted once.
+pub fn get_trace(
+    agent: &Agent,
+    canister_id: &Principal,
+    names: &BTreeMap<u16, String>,
+) -> anyhow::Result<IDLValue> {
+    let input = runtime().block_on(get_trace_inner(agent, canister_id))?;
+    let mut stack: Vec<(i32, i64)> = Vec::new();
+    let mut costs: BTreeMap<String, u64> = BTreeMap::new();
+    for (id, count) in input.into_iter() {
+        if id >= 0 {
+            stack.push((id, count));
+            continue;
+        }
+        let Some((start_id, start)) = stack.pop() else {
+            return Err(anyhow!("pop empty stack"));
+        };
+        if start_id != -id {
+            return Err(anyhow!("func id mismatch"));
+        }
+        if stack.iter().all(|(id, _)| *id != start_id) {
+            *costs.entry(func_name(names, start_id)).or_default() += (count - start) as u64;
         }
-    } else {
-        eprintln!("empty trace");
-        Ok(0)
+    }
+    let mut fs: Vec<_> = costs
+        .into_iter()
+        .map(|(name, cost)| IDLField {
+            id: Label::Named(name),
+            val: IDLValue::Nat64(cost),
+        })
+        .collect();
+    fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+    Ok(IDLValue::Record(fs))
+}
+
+fn func_name(names: &BTreeMap<u16, String>, id: i32) -> String {
+    match names.get(&(id as u16)) {
+        Some(name) => name.clone(),
+        None => "func_".to_string() + &id.to_string(),
     }
 }
 
@@ -98,11 +156,7 @@ fn render_profiling(
     for (id, count) in input.into_iter() {
         if id >= 0 {
             stack.push((id, count, 0));
-            let name = match names.get(&(id as u16)) {
-                Some(name) => name.clone(),
-                None => "func_".to_string() + &id.to_string(),
-            };
-            prefix.push(name);
+            prefix.push(func_name(names, id));
         } else {
             match stack.pop() {
                 None => return Err(anyhow!("pop empty stack")),
@@ -138,6 +192,12 @@ fn render_profiling(
     } else {
         CostValue::Complete(total)
     };
+    // Folded stacks can be post-processed by external flamegraph tools.
+    if filename.extension().is_some_and(|ext| ext == "folded") {
+        println!("Folded stacks written to {}", filename.display());
+        std::fs::write(&filename, result.join("\n") + "\n")?;
+        return Ok(cost);
+    }
     //println!("Cost: {} Wasm instructions", total);
     let mut opt = Options::default();
     opt.count_name = "instructions".to_string();
@@ -161,6 +221,30 @@ fn render_profiling(
     Ok(cost)
 }
 
+/// Render a differential flamegraph from two folded stacks files, e.g., written by `flamegraph`.
+/// Frames that got more expensive in `after` are shown in red, and cheaper ones in blue.
+pub fn render_diff(
+    title: &str,
+    before: PathBuf,
+    after: PathBuf,
+    filename: PathBuf,
+) -> anyhow::Result<()> {
+    use inferno::{
+        differential,
+        flamegraph::{from_reader, Options},
+    };
+    let mut diff = Vec::new();
+    differential::from_files(differential::Options::default(), before, after, &mut diff)?;
+    let mut opt = Options::default();
+    opt.count_name = "instructions".to_string();
+    opt.title = title.to_string();
+    opt.image_width = Some(1024);
+    println!("Flamegraph written to {}", filename.display());
+    let mut writer = std::fs::File::create(&filename)?;
+    from_reader(&mut opt, std::io::Cursor::new(diff), &mut writer)?;
+    Ok(())
+}
+
 pub fn may_extract_profiling(result: IDLValue) -> (IDLValue, Option<i64>) {
     match result {
         IDLValue::Record(ref fs) => match fs.as_slice() {
@@ -11,7 +11,9 @@ use candid::{
 #[derive(Debug, Clone)]
 pub enum Selector {
     Index(Exp),
+    Slice(Option<Exp>, Option<Exp>),
     Field(String),
+    OptField(String),
     Option,
     Map(String),
     Filter(String),
@@ -36,27 +38,29 @@ pub fn project(helper: &MyHelper, value: IDLValue, path: Vec<Selector>) -> Resul
         match (result, head) {
             (IDLValue::Opt(opt), Selector::Option) => result = *opt,
             (IDLValue::Blob(b), Selector::Index(e)) => {
-                let idx = as_u32(&e.eval(helper)?)?;
-                result = IDLValue::Nat8(
-                    *b.get(idx as usize)
-                        .ok_or_else(|| anyhow!("idx out of bound"))?,
-                )
+                let idx = as_index(&e.eval(helper)?, b.len())?;
+                result = IDLValue::Nat8(b[idx]);
             }
             (IDLValue::Vec(mut vs), Selector::Index(e)) => {
-                let idx = as_u32(&e.eval(helper)?)? as usize;
-                if idx < vs.len() {
-                    result = vs.swap_remove(idx);
-                } else {
-                    return Err(anyhow!("{} out of bound {}", idx, vs.len()));
-                }
+                let idx = as_index(&e.eval(helper)?, vs.len())?;
+                result = vs.swap_remove(idx);
             }
             (IDLValue::Text(s), Selector::Index(e)) => {
-                let idx = as_u32(&e.eval(helper)?)? as usize;
-                if idx < s.len() {
-                    result = IDLValue::Text(s.chars().nth(idx).unwrap().to_string());
-                } else {
-                    return Err(anyhow!("{} out of bound {}", idx, s.len()));
-                }
+                let idx = as_index(&e.eval(helper)?, s.chars().count())?;
+                result = IDLValue::Text(s.chars().nth(idx).unwrap().to_string());
+            }
+            (IDLValue::Blob(b), Selector::Slice(start, end)) => {
+                let range = as_range(helper, start, end, b.len())?;
+                result = IDLValue::Blob(b[range].to_vec());
+            }
+            (IDLValue::Vec(vs), Selector::Slice(start, end)) => {
+                let range = as_range(helper, start, end, vs.len())?;
+                result = IDLValue::Vec(vs[range].to_vec());
+            }
+            (IDLValue::Text(s), Selector::Slice(start, end)) => {
+                let range = as_range(helper, start, end, s.chars().count())?;
+                let len = range.len();
+                result = IDLValue::Text(s.chars().skip(range.start).take(len).collect());
             }
             (IDLValue::Blob(b), Selector::Map(func)) => {
                 let vs = b.into_iter().map(IDLValue::Nat8).collect();
@@ -137,6 +141,30 @@ pub fn project(helper: &MyHelper, value: IDLValue, path: Vec<Selector>) -> Resul
                     return Err(anyhow!("variant field {:?} not found", field));
                 }
             }
+            (value, Selector::OptField(name)) => {
+                let value = match value {
+                    IDLValue::Opt(v) => *v,
+                    IDLValue::None | IDLValue::Null => return Ok(IDLValue::Null),
+                    v => v,
+                };
+                let id = Label::Named(name);
+                match value {
+                    IDLValue::Record(fs) => match fs.into_iter().find(|f| f.id == id) {
+                        Some(f) => result = f.val,
+                        None => return Ok(IDLValue::Null),
+                    },
+                    IDLValue::Variant(VariantValue(f, _)) => {
+                        if f.id == id {
+                            result = f.val;
+                        } else {
+                            return Ok(IDLValue::Null);
+                        }
+                    }
+                    value => {
+                        return Err(anyhow!("selector .?{} cannot be applied to {}", id, value))
+                    }
+                }
+            }
             (value, head) => {
                 return Err(anyhow!(
                     "selector {:?} cannot be applied to {}",
@@ -149,6 +177,39 @@ pub fn project(helper: &MyHelper, value: IDLValue, path: Vec<Selector>) -> Resul
     Ok(result)
 }
 
+/// Resolve an index into a collection of size `len`. Negative indices count from the end.
+fn as_index(v: &IDLValue, len: usize) -> Result<usize> {
+    let idx = as_offset(v, len)?;
+    if (0..len as i64).contains(&idx) {
+        Ok(idx as usize)
+    } else {
+        Err(anyhow!("{} out of bound {}", v, len))
+    }
+}
+/// Resolve `[start..end]` into a range clamped to `0..len`. Missing bounds default to the start/end of the collection.
+fn as_range(
+    helper: &MyHelper,
+    start: Option<Exp>,
+    end: Option<Exp>,
+    len: usize,
+) -> Result<std::ops::Range<usize>> {
+    let bound = |e: Option<Exp>, default: usize| -> Result<usize> {
+        Ok(match e {
+            Some(e) => as_offset(&e.eval(helper)?, len)?.clamp(0, len as i64) as usize,
+            None => default,
+        })
+    };
+    let start = bound(start, 0)?;
+    let end = bound(end, len)?;
+    Ok(start..end.max(start))
+}
+fn as_offset(v: &IDLValue, len: usize) -> Result<i64> {
+    let idx = match v {
+        IDLValue::Number(n) => n.parse::<i64>()?,
+        _ => as_u32(v)? as i64,
+    };
+    Ok(if idx < 0 { idx + len as i64 } else { idx })
+}
 fn from_fields(fs: Vec<IDLField>) -> Vec<IDLValue> {
     fs.into_iter()
         .map(|f| {
@@ -250,3 +311,51 @@ fn fold(helper: &MyHelper, init: Exp, vs: Vec<IDLValue>, func: &str) -> Result<I
     }
     Ok(acc)
 }
+
+#[test]
+fn test_index_and_slice() -> Result<()> {
+    use candid_parser::parse_idl_value;
+    let mut helper = crate::helper::test_helper();
+    let env = [
+        ("v", "vec { 10; 20; 30; 40 }"),
+        ("t", r#""héllo""#),
+        ("b", r#"blob "\01\02\03""#),
+    ];
+    for (name, value) in env {
+        helper
+            .env
+            .0
+            .insert(name.to_string(), parse_idl_value(value)?);
+    }
+    let eval = |s: &str| s.parse::<Exp>().unwrap().eval(&helper);
+    let check = |s: &str, expected: &str| {
+        assert_eq!(eval(s).unwrap(), parse_idl_value(expected).unwrap(), "{s}");
+    };
+    // negative indices count from the end
+    check("v[-1]", "40");
+    check("v[-4]", "10");
+    check("t[-1]", r#""o""#);
+    assert_eq!(eval("b[-1]")?, IDLValue::Nat8(3));
+    assert!(eval("v[-5]").is_err());
+    assert!(eval("t[-6]").is_err());
+    // indices beyond the length are rejected
+    assert!(eval("v[4]").is_err());
+    assert!(eval("t[5]").is_err());
+    assert!(eval("b[3]").is_err());
+    // slices are end-exclusive, and text is sliced by chars
+    check("v[1..3]", "vec { 20; 30 }");
+    check("v[..2]", "vec { 10; 20 }");
+    check("v[-2..]", "vec { 30; 40 }");
+    check("t[1..3]", r#""él""#);
+    check("t[-3..]", r#""llo""#);
+    check("b[1..]", r#"blob "\02\03""#);
+    check("b[..-1]", r#"blob "\01\02""#);
+    // bounds are clamped, and empty or reversed ranges give an empty result
+    check("v[2..10]", "vec { 30; 40 }");
+    check("v[-10..1]", "vec { 10 }");
+    check("v[2..2]", "vec {}");
+    check("v[3..1]", "vec {}");
+    check("t[3..1]", r#""""#);
+    check("b[2..2]", r#"blob """#);
+    Ok(())
+}
@@ -33,6 +33,10 @@ pub enum Token {
     Colon,
     #[token("->")]
     Arrow,
+    #[token("..")]
+    DotDot,
+    #[token("=>")]
+    FatArrow,
     #[token("null")]
     Null,
     #[token("vec")]
@@ -65,12 +69,16 @@ pub enum Token {
     Call,
     #[token("par_call")]
     ParCall,
+    #[token("async")]
+    Async,
     #[token("encode")]
     Encode,
     #[token("decode")]
     Decode,
     #[token("as")]
     As,
+    #[token("with")]
+    With,
     #[token("config")]
     Config,
     #[token("let")]
@@ -81,12 +89,30 @@ pub enum Token {
     Identity,
     #[token("load")]
     Load,
+    #[token("include")]
+    Include,
+    #[token("print")]
+    Print,
+    #[token("println")]
+    Println,
+    #[token("show")]
+    Show,
+    #[token("unset")]
+    Unset,
+    #[token("refresh")]
+    Refresh,
     #[token("function")]
     Function,
     #[token("while")]
     While,
+    #[token("for")]
+    For,
+    #[token("in")]
+    In,
     #[token("if")]
     If,
+    #[token("match")]
+    Match,
     #[token("else")]
     Else,
     #[token("==")]
@@ -95,6 +121,14 @@ pub enum Token {
     SubEqual,
     #[token("!=")]
     NotEqual,
+    #[token("<")]
+    Less,
+    #[token("<=")]
+    LessEqual,
+    #[token(">")]
+    Greater,
+    #[token(">=")]
+    GreaterEqual,
     #[token("principal")]
     Principal,
     #[regex("[a-zA-Z_][a-zA-Z0-9_]*", |lex| lex.slice().to_string())]
@@ -103,12 +137,17 @@ pub enum Token {
     StartString,
     // This token is not derived. Stores the unescaped string
     Text(String),
+    // This token is not derived. Stores a string with `\(exp)` interpolations
+    TextTemplate(Vec<TextPart>),
     #[regex("[+-]", |lex| lex.slice().chars().next())]
     Sign(char),
     #[regex("[0-9][_0-9]*", parse_number)]
     Decimal(String),
     #[regex("0[xX][0-9a-fA-F][_0-9a-fA-F]*", parse_number)]
     Hex(String),
+    // Starting bound of a range like `1..3`, which would otherwise be lexed as a float `1.`
+    #[regex("[0-9][_0-9]*\\.\\.", |lex| parse_number(lex).trim_end_matches('.').to_string())]
+    RangeFrom(String),
     #[regex("[0-9]*\\.[0-9]*", parse_number)]
     #[regex("[0-9]+(\\.[0-9]*)?[eE][+-]?[0-9]+", parse_number)]
     Float(String),
@@ -116,6 +155,13 @@ pub enum Token {
     Boolean(bool),
 }
 
+/// A piece of an interpolated string: either unescaped text or the source of an embedded expression.
+#[derive(Debug, Clone, PartialEq, Eq, Ord, PartialOrd)]
+pub enum TextPart {
+    Text(String),
+    Exp(String),
+}
+
 #[derive(Logos, Debug, Clone, PartialEq, Eq)]
 enum Comment {
     #[token("*/")]
@@ -156,12 +202,169 @@ fn parse_number(lex: &mut Lexer<Token>) -> String {
 
 pub struct Tokenizer<'input> {
     lex: Lexer<'input, Token>,
+    /// Whether the input starts with a command, instead of an expression.
+    command: bool,
+    /// The last two emitted tokens, used to decide if a contextual keyword is a keyword.
+    prev: Option<Token>,
+    prev2: Option<Token>,
+    /// For each open brace, whether it starts a value literal, e.g., `record {`, instead of a block of commands.
+    braces: Vec<bool>,
+    /// Number of open parentheses, brackets and braces.
+    depth: usize,
+    /// Depths of the `match` keywords whose arms have not started yet.
+    matches: Vec<usize>,
 }
 impl<'input> Tokenizer<'input> {
+    /// Tokenizer for commands.
     pub fn new(input: &'input str) -> Self {
-        let lex = Token::lexer(input);
-        Tokenizer { lex }
+        Tokenizer {
+            lex: Token::lexer(input),
+            command: true,
+            prev: None,
+            prev2: None,
+            braces: Vec::new(),
+            depth: 0,
+            matches: Vec::new(),
+        }
+    }
+    /// Tokenizer for a single expression.
+    pub fn new_exp(input: &'input str) -> Self {
+        Tokenizer {
+            command: false,
+            ..Tokenizer::new(input)
+        }
     }
+    /// Keywords introduced after the initial release are contextual: they are only lexed as keywords
+    /// where the grammar uses them, and as identifiers elsewhere, so that existing scripts can still use
+    /// them as variable, field and method names.
+    fn contextual(&self, token: Token) -> Token {
+        let is_keyword = match token {
+            Token::Show
+            | Token::Unset
+            | Token::Refresh
+            | Token::For
+            | Token::Include
+            | Token::Print
+            | Token::Println => self.at_command_start() && !ends_name(self.peek().as_ref()),
+            Token::In => matches!(
+                (&self.prev2, &self.prev),
+                (Some(Token::For), Some(Token::Id(_)))
+            ),
+            Token::Async => matches!(self.prev, Some(Token::Call)),
+            Token::With => {
+                matches!(self.prev, Some(Token::Id(_) | Token::Text(_) | Token::Call))
+                    && matches!(self.peek(), Some(Token::Id(_)))
+            }
+            Token::Match => {
+                !matches!(
+                    self.prev,
+                    Some(Token::Dot | Token::Question | Token::Let | Token::Function | Token::For)
+                ) && match self.peek() {
+                    Some(Token::LParen) => self.token_after_parens() == Some(Token::LBrace),
+                    next => {
+                        next.is_some()
+                            && !ends_name(next.as_ref())
+                            && !matches!(next, Some(Token::Semi | Token::LBrace | Token::RBrace))
+                    }
+                }
+            }
+            _ => return token,
+        };
+        if is_keyword {
+            token
+        } else {
+            Token::Id(self.lex.slice().to_string())
+        }
+    }
+    fn at_command_start(&self) -> bool {
+        match self.prev {
+            None => self.command,
+            Some(Token::Semi | Token::LBrace) => self.braces.last() != Some(&true),
+            _ => false,
+        }
+    }
+    fn peek(&self) -> Option<Token> {
+        self.lex.clone().next()?.ok()
+    }
+    /// The token after the parenthesized expression that follows the current token,
+    /// to tell `match (e) { ... }` from calling a function named `match`.
+    fn token_after_parens(&self) -> Option<Token> {
+        let mut lookahead = Tokenizer {
+            lex: self.lex.clone(),
+            command: false,
+            prev: None,
+            prev2: None,
+            braces: Vec::new(),
+            depth: 0,
+            matches: Vec::new(),
+        };
+        let mut depth = 0;
+        while let Some(Ok((_, token, _))) = lookahead.next_token() {
+            match token {
+                Token::LParen => depth += 1,
+                Token::RParen if depth == 1 => return lookahead.next_token()?.ok().map(|t| t.1),
+                Token::RParen => depth -= 1,
+                _ => (),
+            }
+        }
+        None
+    }
+    fn track(&mut self, token: &Token) {
+        match token {
+            Token::Match => self.matches.push(self.depth),
+            Token::LBrace => {
+                let mut literal = matches!(
+                    self.prev,
+                    Some(
+                        Token::Record | Token::Vec | Token::Variant | Token::Service | Token::Colon
+                    )
+                );
+                // The arms of a match are not commands either.
+                if !literal && self.matches.last() == Some(&self.depth) {
+                    self.matches.pop();
+                    literal = true;
+                }
+                self.braces.push(literal);
+                self.depth += 1;
+            }
+            Token::LParen | Token::LSquare => self.depth += 1,
+            Token::RBrace => {
+                self.braces.pop();
+                self.depth = self.depth.saturating_sub(1);
+            }
+            Token::RParen | Token::RSquare => self.depth = self.depth.saturating_sub(1),
+            _ => (),
+        }
+        self.prev2 = self.prev.replace(token.clone());
+    }
+}
+
+/// Tokens after which a contextual keyword can only be a name, e.g., `show = 1` in a record literal,
+/// or `print(x)` calling a user-defined function.
+fn ends_name(next: Option<&Token>) -> bool {
+    use Token::*;
+    matches!(
+        next,
+        Some(
+            Equals
+                | LParen
+                | Dot
+                | Question
+                | LSquare
+                | Colon
+                | FatArrow
+                | Comma
+                | RParen
+                | RSquare
+                | TestEqual
+                | SubEqual
+                | NotEqual
+                | Less
+                | LessEqual
+                | Greater
+                | GreaterEqual
+        )
+    )
 }
 
 pub type Span = std::ops::Range<usize>;
@@ -198,6 +401,17 @@ pub fn error2<E: ToString>(err: E, span: Span) -> ParserError {
 impl Iterator for Tokenizer<'_> {
     type Item = Result<(usize, Token, usize), LexicalError>;
     fn next(&mut self) -> Option<Self::Item> {
+        let res = self.next_token()?;
+        let res = res.map(|(start, token, end)| (start, self.contextual(token), end));
+        if let Ok((_, token, _)) = &res {
+            self.track(token);
+        }
+        Some(res)
+    }
+}
+
+impl Tokenizer<'_> {
+    fn next_token(&mut self) -> Option<<Self as Iterator>::Item> {
         let token = self.lex.next()?;
         let span = self.lex.span();
         match token {
@@ -227,10 +441,11 @@ impl Iterator for Tokenizer<'_> {
                     }
                 }
                 self.lex = lex.morph::<Token>();
-                self.next()
+                self.next_token()
             }
             Ok(Token::StartString) => {
                 let mut result = String::new();
+                let mut parts = Vec::new();
                 let mut lex = self.lex.to_owned().morph::<Text>();
                 loop {
                     use self::Text::*;
@@ -243,6 +458,19 @@ impl Iterator for Tokenizer<'_> {
                             '\\' => result.push('\\'),
                             '"' => result.push('"'),
                             '\'' => result.push('\''),
+                            '(' => match interpolation_end(lex.remainder()) {
+                                Some(end) => {
+                                    parts.push(TextPart::Text(std::mem::take(&mut result)));
+                                    parts.push(TextPart::Exp(lex.remainder()[..end].to_string()));
+                                    lex.bump(end + 1);
+                                }
+                                None => {
+                                    return Some(Err(LexicalError::new(
+                                        "Unclosed interpolation",
+                                        lex.span().start..lex.source().len(),
+                                    )))
+                                }
+                            },
                             c => {
                                 return Some(Err(LexicalError::new(
                                     format!("Unknown escape character {c}"),
@@ -302,9 +530,98 @@ impl Iterator for Tokenizer<'_> {
                     }
                 }
                 self.lex = lex.morph::<Token>();
-                Some(Ok((span.start, Token::Text(result), self.lex.span().end)))
+                let token = if parts.is_empty() {
+                    Token::Text(result)
+                } else {
+                    parts.push(TextPart::Text(result));
+                    Token::TextTemplate(parts)
+                };
+                Some(Ok((span.start, token, self.lex.span().end)))
             }
             Ok(token) => Some(Ok((span.start, token, span.end))),
         }
     }
 }
+
+/// Find the closing parenthesis of an interpolation `\(exp)`, skipping over nested parentheses and strings.
+fn interpolation_end(src: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in src.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '(' => depth += 1,
+            ')' if depth == 0 => return Some(i),
+            ')' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+#[test]
+fn test_contextual_keywords() {
+    fn tokens(tokenizer: Tokenizer) -> Vec<Token> {
+        tokenizer.map(|t| t.unwrap().1).collect()
+    }
+    let id = |s: &str| Token::Id(s.to_string());
+    assert_eq!(tokens(Tokenizer::new("show"))[0], Token::Show);
+    assert_eq!(tokens(Tokenizer::new("if x { show }"))[3], Token::Show);
+    assert_eq!(tokens(Tokenizer::new_exp("show")), vec![id("show")]);
+    assert_eq!(tokens(Tokenizer::new("let show = 1"))[1], id("show"));
+    assert_eq!(tokens(Tokenizer::new("x.show(1)"))[2], id("show"));
+    assert_eq!(
+        tokens(Tokenizer::new("record { a = 1; show }"))[6],
+        id("show")
+    );
+    assert_eq!(tokens(Tokenizer::new("show.field"))[0], id("show"));
+    assert_eq!(tokens(Tokenizer::new("unset x"))[0], Token::Unset);
+    assert_eq!(tokens(Tokenizer::new("let unset = unset"))[3], id("unset"));
+    assert_eq!(tokens(Tokenizer::new("refresh ic"))[0], Token::Refresh);
+    let for_in = tokens(Tokenizer::new("for in in in { let for = in }"));
+    assert_eq!(&for_in[..4], [Token::For, id("in"), Token::In, id("in")]);
+    assert_eq!(&for_in[6..9], [id("for"), Token::Equals, id("in")]);
+    let tags = |s: &str| tokens(Tokenizer::new(s));
+    assert_eq!(tags("match x { show(v) => v }")[0], Token::Match);
+    assert_eq!(tags("match x { show(v) => v }")[3], id("show"));
+    assert_eq!(tags("match (f(x)) { a => 1 }")[0], Token::Match);
+    assert_eq!(
+        tags("match variant { a } { a => 1; show => 2 }")[10],
+        id("show")
+    );
+    assert_eq!(tags("match(x)")[0], id("match"));
+    assert_eq!(tags("let match = x.match; f(match)")[1], id("match"));
+    assert_eq!(tags("let match = x.match; f(match)")[5], id("match"));
+    assert_eq!(tags("let match = x.match; f(match)")[9], id("match"));
+    assert_eq!(
+        tokens(Tokenizer::new("call ic.refresh()"))[3],
+        id("refresh")
+    );
+    assert_eq!(tags("call async a.f()")[1], Token::Async);
+    assert_eq!(tags("let async = a.async")[1], id("async"));
+    assert_eq!(tags("let async = a.async")[5], id("async"));
+    assert_eq!(tags("call as w with cycles = 1 a.f()")[3], Token::With);
+    assert_eq!(tags("call with cycles = 1 a.f()")[1], Token::With);
+    assert_eq!(tags("let with = r.with")[1], id("with"));
+    assert_eq!(tags("let with = r.with")[5], id("with"));
+    assert_eq!(tags("include \"a.sh\"")[0], Token::Include);
+    assert_eq!(tags("let include = r.include")[1], id("include"));
+    assert_eq!(tags("let include = r.include")[5], id("include"));
+    assert_eq!(tags("print x")[0], Token::Print);
+    assert_eq!(tags("if x { println \"y\" }")[3], Token::Println);
+    assert_eq!(tags("let print = r.println")[1], id("print"));
+    assert_eq!(tags("let print = r.println")[5], id("println"));
+    for name in ["print", "println", "show", "include", "unset", "refresh"] {
+        assert_eq!(tags(&format!("{name}(x)"))[0], id(name));
+    }
+}
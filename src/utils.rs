@@ -5,10 +5,20 @@ use candid::types::value::{IDLArgs, IDLField, IDLValue};
 use candid::types::{Label, Type, TypeInner};
 use candid::{Principal, TypeEnv};
 use candid_parser::configs::Configs;
-use ic_agent::Agent;
+use ic_agent::{Agent, Identity};
 use std::borrow::Cow;
 use std::path::{Path, PathBuf};
 use std::slice;
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::Runtime;
+
+/// The inferred candid type of a value. Numbers without a type annotation are reported as `number`.
+pub fn type_of(v: &IDLValue) -> String {
+    match v {
+        IDLValue::Number(_) => "number".to_string(),
+        _ => v.value_ty().to_string(),
+    }
+}
 
 pub fn stringify(v: &IDLValue) -> anyhow::Result<Cow<'_, str>> {
     Ok(match v {
@@ -40,6 +50,326 @@ pub fn stringify(v: &IDLValue) -> anyhow::Result<Cow<'_, str>> {
     })
 }
 
+/// Compare two numbers, converting to float if either side is a float. Returns `None` for NaN.
+pub fn num_cmp(v1: &IDLValue, v2: &IDLValue) -> Result<Option<std::cmp::Ordering>> {
+    Ok(match (v1, v2) {
+        (IDLValue::Float32(_) | IDLValue::Float64(_), _)
+        | (_, IDLValue::Float32(_) | IDLValue::Float64(_)) => {
+            let ty = TypeInner::Float64.into();
+            match (cast_type(v1.clone(), &ty)?, cast_type(v2.clone(), &ty)?) {
+                (IDLValue::Float64(v1), IDLValue::Float64(v2)) => v1.partial_cmp(&v2),
+                _ => unreachable!(),
+            }
+        }
+        _ => {
+            let ty = TypeInner::Int.into();
+            match (cast_type(v1.clone(), &ty), cast_type(v2.clone(), &ty)) {
+                (Ok(IDLValue::Int(v1)), Ok(IDLValue::Int(v2))) => Some(v1.cmp(&v2)),
+                _ => return Err(anyhow!("cannot compare {v1} and {v2} as numbers")),
+            }
+        }
+    })
+}
+
+/// Compare two cost records field by field. Returns `record { diff; regressions }`, where `diff` maps each
+/// field to `record { before; after; delta }`, and `regressions` lists the fields whose cost increased.
+/// A field missing from one side counts as zero.
+pub fn cost_diff(before: &[IDLField], after: &[IDLField]) -> Result<IDLValue> {
+    use candid::Int;
+    let cost = |fs: &[IDLField], id: &Label| -> Result<Int> {
+        match fs.iter().find(|f| f.id == *id) {
+            None => Ok(Int::from(0)),
+            Some(f) => match cast_type(f.val.clone(), &TypeInner::Int.into()) {
+                Ok(IDLValue::Int(n)) => Ok(n),
+                _ => Err(anyhow!("cost of {id} is not a number")),
+            },
+        }
+    };
+    let mut ids: Vec<_> = before.iter().chain(after).map(|f| f.id.clone()).collect();
+    ids.sort_unstable_by_key(|id| id.get_id());
+    ids.dedup_by_key(|id| id.get_id());
+    let mut diff = Vec::with_capacity(ids.len());
+    let mut regressions = Vec::new();
+    for id in ids {
+        let b = cost(before, &id)?;
+        let a = cost(after, &id)?;
+        let delta = a.clone() - b.clone();
+        if delta > 0 {
+            regressions.push(IDLValue::Text(match &id {
+                Label::Named(name) => name.clone(),
+                id => id.to_string(),
+            }));
+        }
+        let mut fs = vec![
+            IDLField {
+                id: Label::Named("before".to_string()),
+                val: IDLValue::Int(b),
+            },
+            IDLField {
+                id: Label::Named("after".to_string()),
+                val: IDLValue::Int(a),
+            },
+            IDLField {
+                id: Label::Named("delta".to_string()),
+                val: IDLValue::Int(delta),
+            },
+        ];
+        fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+        diff.push(IDLField {
+            id,
+            val: IDLValue::Record(fs),
+        });
+    }
+    let mut res = vec![
+        IDLField {
+            id: Label::Named("diff".to_string()),
+            val: IDLValue::Record(diff),
+        },
+        IDLField {
+            id: Label::Named("regressions".to_string()),
+            val: IDLValue::Vec(regressions),
+        },
+    ];
+    res.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+    Ok(IDLValue::Record(res))
+}
+
+const PERF_FILE: &str = "perf_records.did";
+
+fn read_perf_records(path: &Path) -> Result<Vec<IDLValue>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = std::fs::read_to_string(path)?;
+    Ok(candid_parser::parse_idl_args(&content)
+        .with_context(|| format!("Cannot parse {path:?}"))?
+        .args)
+}
+
+/// Append `record { tag; metrics }` to `perf_records.did` in the current directory.
+pub fn perf_record(tag: &str, metrics: Vec<IDLField>) -> Result<()> {
+    let path = std::env::current_dir()?.join(PERF_FILE);
+    let mut records = read_perf_records(&path)?;
+    let mut fs = vec![
+        IDLField {
+            id: Label::Named("tag".to_string()),
+            val: IDLValue::Text(tag.to_string()),
+        },
+        IDLField {
+            id: Label::Named("metrics".to_string()),
+            val: IDLValue::Record(metrics),
+        },
+    ];
+    fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+    records.push(IDLValue::Record(fs));
+    std::fs::write(&path, format!("{}\n", IDLArgs::new(&records)))?;
+    Ok(())
+}
+
+/// Diff the metrics of the latest records tagged `tag_a` and `tag_b` with `cost_diff`.
+pub fn perf_compare(tag_a: &str, tag_b: &str) -> Result<IDLValue> {
+    let path = std::env::current_dir()?.join(PERF_FILE);
+    let records = read_perf_records(&path)?;
+    let find = |tag: &str| -> Result<Vec<IDLField>> {
+        records
+            .iter()
+            .rev()
+            .find_map(|r| match r {
+                IDLValue::Record(fs) => match (get_field(fs, "tag"), get_field(fs, "metrics")) {
+                    (Some(IDLValue::Text(t)), Some(IDLValue::Record(metrics))) if t == tag => {
+                        Some(metrics.clone())
+                    }
+                    _ => None,
+                },
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("Cannot find perf record {tag} in {path:?}"))
+    };
+    cost_diff(&find(tag_a)?, &find(tag_b)?)
+}
+
+/// Format a record of numeric metrics in the Prometheus text exposition format.
+pub fn to_prometheus(metrics: &[IDLField], labels: &[IDLField]) -> Result<String> {
+    use std::fmt::Write;
+    let mut label_str = Vec::with_capacity(labels.len());
+    for IDLField { id, val } in labels {
+        let Label::Named(name) = id else {
+            return Err(anyhow!("prometheus labels must have named fields"));
+        };
+        let val = stringify(val)?
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        label_str.push(format!("{name}=\"{val}\""));
+    }
+    let label_str = if label_str.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", label_str.join(","))
+    };
+    let mut res = String::new();
+    for IDLField { id, val } in metrics {
+        let Label::Named(name) = id else {
+            return Err(anyhow!("prometheus metrics must have named fields"));
+        };
+        let val = num_cast_helper(val.clone(), false)
+            .with_context(|| format!("metric {name} is not a number"))?;
+        writeln!(&mut res, "{name}{label_str} {val}")?;
+    }
+    Ok(res)
+}
+
+/// Build a binary Merkle tree with SHA-256. Leaves are the hashes of each blob, and an unpaired node
+/// is promoted to the next level unchanged. Returns all levels from leaves to root.
+fn merkle_levels(leaves: &[Vec<u8>]) -> Result<Vec<Vec<Vec<u8>>>> {
+    use sha2::{Digest, Sha256};
+    if leaves.is_empty() {
+        return Err(anyhow!("merkle tree expects at least one leaf"));
+    }
+    let mut levels = vec![leaves
+        .iter()
+        .map(|leaf| Sha256::digest(leaf).to_vec())
+        .collect::<Vec<_>>()];
+    while levels.last().unwrap().len() > 1 {
+        let next = levels
+            .last()
+            .unwrap()
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().to_vec()
+                }
+                [node] => node.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        levels.push(next);
+    }
+    Ok(levels)
+}
+
+pub fn merkle_root(leaves: &[Vec<u8>]) -> Result<Vec<u8>> {
+    Ok(merkle_levels(leaves)?.pop().unwrap().pop().unwrap())
+}
+
+/// Returns the inclusion proof as a list of (sibling hash, whether the sibling is on the left) from leaf to root.
+pub fn merkle_proof(leaves: &[Vec<u8>], mut index: usize) -> Result<Vec<(Vec<u8>, bool)>> {
+    if index >= leaves.len() {
+        return Err(anyhow!("index {index} out of bound {}", leaves.len()));
+    }
+    let levels = merkle_levels(leaves)?;
+    let mut proof = Vec::new();
+    for level in &levels[..levels.len() - 1] {
+        let sibling = index ^ 1;
+        if sibling < level.len() {
+            proof.push((level[sibling].clone(), sibling < index));
+        }
+        index /= 2;
+    }
+    Ok(proof)
+}
+
+const E8S_PER_ICP: u64 = 100_000_000;
+
+pub fn e8s_to_icp(e8s: u64) -> String {
+    format!("{}.{:08}", e8s / E8S_PER_ICP, e8s % E8S_PER_ICP)
+}
+
+pub fn icp_to_e8s(icp: &str) -> Result<u64> {
+    let icp = icp.trim().replace('_', "");
+    let (int, frac) = icp.split_once('.').unwrap_or((&icp, ""));
+    let is_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if int.is_empty() && frac.is_empty() || !is_digits(int) || !is_digits(frac) {
+        return Err(anyhow!("{icp} is not a valid ICP amount"));
+    }
+    if frac.len() > 8 {
+        return Err(anyhow!("{icp} has more than 8 decimal places"));
+    }
+    let int = if int.is_empty() {
+        0
+    } else {
+        int.parse::<u64>()?
+    };
+    let frac = format!("{frac:0<8}").parse::<u64>()?;
+    int.checked_mul(E8S_PER_ICP)
+        .and_then(|e8s| e8s.checked_add(frac))
+        .ok_or_else(|| anyhow!("{icp} ICP overflows nat64 e8s"))
+}
+
+/// Encode an integer into `bits / 8` bytes. Negative numbers use two's complement.
+pub fn int_to_bytes(v: &IDLValue, bits: u32, little_endian: bool) -> Result<Vec<u8>> {
+    if ![8, 16, 32, 64].contains(&bits) {
+        return Err(anyhow!("width can only be 8, 16, 32 or 64"));
+    }
+    let n = num_cast_helper(v.clone(), false)?
+        .parse::<i128>()
+        .map_err(|_| anyhow!("{v} is not an integer"))?;
+    if n >= 1i128 << bits || n < -(1i128 << (bits - 1)) {
+        return Err(anyhow!("{v} overflows {bits} bits"));
+    }
+    let len = (bits / 8) as usize;
+    let bytes = (n as u128).to_le_bytes()[..len].to_vec();
+    Ok(if little_endian {
+        bytes
+    } else {
+        bytes.into_iter().rev().collect()
+    })
+}
+/// Decode a 1, 2, 4 or 8 byte blob as an unsigned integer.
+pub fn bytes_to_int(blob: &[u8], little_endian: bool) -> Result<IDLValue> {
+    if ![1, 2, 4, 8].contains(&blob.len()) {
+        return Err(anyhow!("blob can only be 1, 2, 4 or 8 bytes"));
+    }
+    let mut bytes = [0u8; 8];
+    if little_endian {
+        bytes[..blob.len()].copy_from_slice(blob);
+    } else {
+        bytes[8 - blob.len()..].copy_from_slice(blob);
+    }
+    let n = if little_endian {
+        u64::from_le_bytes(bytes)
+    } else {
+        u64::from_be_bytes(bytes)
+    };
+    Ok(IDLValue::Number(n.to_string()))
+}
+
+/// Encode a number in unsigned LEB128, or signed LEB128 when `signed` is true.
+pub fn leb128_encode(v: &IDLValue, signed: bool) -> Result<Vec<u8>> {
+    let mut res = Vec::new();
+    if signed {
+        let IDLValue::Int(n) = cast_type(v.clone(), &TypeInner::Int.into())? else {
+            unreachable!()
+        };
+        n.encode(&mut res)?;
+    } else {
+        let IDLValue::Nat(n) = cast_type(v.clone(), &TypeInner::Nat.into())? else {
+            unreachable!()
+        };
+        n.encode(&mut res)?;
+    }
+    Ok(res)
+}
+/// Decode an unsigned or signed LEB128 blob. The blob must contain exactly one number.
+pub fn leb128_decode(blob: &[u8], signed: bool) -> Result<IDLValue> {
+    let mut reader = blob;
+    let n = if signed {
+        candid::Int::decode(&mut reader)?.to_string()
+    } else {
+        candid::Nat::decode(&mut reader)?.to_string()
+    };
+    if !reader.is_empty() {
+        return Err(anyhow!(
+            "leb128_decode: {} trailing bytes after the number",
+            reader.len()
+        ));
+    }
+    Ok(IDLValue::Number(n.replace('_', "")))
+}
+
 fn num_cast_helper(v: IDLValue, truncate_float: bool) -> Result<String> {
     Ok(match v {
         IDLValue::Number(n) => n.replace('_', ""),
@@ -209,12 +539,41 @@ pub fn as_u32(v: &IDLValue) -> Result<u32> {
     }
 }
 
+pub fn as_blob(v: IDLValue) -> Result<Vec<u8>> {
+    if *v.value_ty() != TypeInner::Vec(TypeInner::Nat8.into()) {
+        return Err(anyhow!("not a blob"));
+    }
+    Ok(match v {
+        IDLValue::Blob(b) => b,
+        IDLValue::Vec(vs) => vs
+            .into_iter()
+            .map(|v| match v {
+                IDLValue::Nat8(u) => u,
+                _ => unreachable!(),
+            })
+            .collect(),
+        _ => unreachable!(),
+    })
+}
+
 pub fn get_field<'a>(fs: &'a [IDLField], key: &'a str) -> Option<&'a IDLValue> {
     fs.iter()
         .find(|f| f.id == Label::Named(key.to_string()))
         .map(|f| &f.val)
 }
 
+pub fn field(name: &str, val: IDLValue) -> IDLField {
+    IDLField {
+        id: Label::Named(name.to_string()),
+        val,
+    }
+}
+
+pub fn record(mut fs: Vec<IDLField>) -> IDLValue {
+    fs.sort_unstable_by_key(|IDLField { id, .. }| id.get_id());
+    IDLValue::Record(fs)
+}
+
 pub fn args_to_value(mut args: IDLArgs) -> IDLValue {
     match args.args.len() {
         0 => IDLValue::Null,
@@ -245,6 +604,34 @@ pub fn random_value(
     Ok(result.args[0].to_string())
 }
 
+/// Match `name` against a glob pattern, where `*` matches any sequence of characters and `?` matches a single character.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<_>, Vec<_>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut i, mut j) = (0, 0);
+    let mut backtrack = None;
+    while j < n.len() {
+        match p.get(i) {
+            Some('*') => {
+                backtrack = Some((i, j));
+                i += 1;
+            }
+            Some(c) if *c == '?' || *c == n[j] => {
+                i += 1;
+                j += 1;
+            }
+            _ => match backtrack {
+                Some((bi, bj)) => {
+                    backtrack = Some((bi, bj + 1));
+                    i = bi + 1;
+                    j = bj + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    p[i..].iter().all(|c| *c == '*')
+}
+
 pub fn resolve_path(base: &Path, file: &str) -> PathBuf {
     let file = PathBuf::from(shellexpand::tilde(file).into_owned());
     if file.is_absolute() {
@@ -254,6 +641,233 @@ pub fn resolve_path(base: &Path, file: &str) -> PathBuf {
     }
 }
 
+/// List the files matching a glob pattern, where `**` matches any number of directories.
+/// A relative pattern is resolved against `base`, and its matches are returned relative to `base`.
+/// Hidden entries are skipped unless the pattern component starts with `.`.
+pub fn glob_files(base: &Path, pattern: &str) -> Vec<PathBuf> {
+    fn walk(dir: &Path, comps: &[String], res: &mut Vec<PathBuf>) {
+        let Some((pat, rest)) = comps.split_first() else {
+            if dir.is_file() {
+                res.push(dir.to_path_buf());
+            }
+            return;
+        };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        if pat == "**" {
+            walk(dir, rest, res);
+        }
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') && !pat.starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            if pat == "**" {
+                walk(&path, if path.is_dir() { comps } else { rest }, res);
+            } else if glob_match(pat, &name) {
+                walk(&path, rest, res);
+            }
+        }
+    }
+    let full = resolve_path(base, pattern);
+    let mut root = PathBuf::new();
+    let mut comps = Vec::new();
+    for c in full.components() {
+        let s = c.as_os_str().to_string_lossy();
+        if comps.is_empty() && !s.contains(['*', '?']) {
+            root.push(c);
+        } else {
+            comps.push(s.into_owned());
+        }
+    }
+    let mut res = Vec::new();
+    walk(&root, &comps, &mut res);
+    if Path::new(pattern).is_relative() && !pattern.starts_with('~') {
+        res = res
+            .into_iter()
+            .map(|p| p.strip_prefix(base).map(Path::to_path_buf).unwrap_or(p))
+            .collect();
+    }
+    res.sort();
+    res.dedup();
+    res
+}
+
+/// Shared multi-threaded runtime for all agent calls. The number of worker threads defaults to 10,
+/// and can be changed by the `IC_REPL_WORKER_THREADS` environment variable.
+pub fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        let worker_threads = std::env::var("IC_REPL_WORKER_THREADS")
+            .ok()
+            .and_then(|n| n.parse::<usize>().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(10);
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .enable_all()
+            .build()
+            .expect("Unable to create a runtime")
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: std::time::Duration,
+}
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            base_delay: std::time::Duration::from_millis(500),
+        }
+    }
+}
+
+/// Transport failures, timeouts and 5xx responses from the boundary node. Canister rejects are never transient.
+fn is_transient(e: &ic_agent::AgentError) -> bool {
+    use ic_agent::AgentError::*;
+    match e {
+        TransportError(_) | TimeoutWaitingForResponse() => true,
+        HttpError(payload) => payload.status >= 500,
+        _ => false,
+    }
+}
+
+/// Fail the call with a timeout error if it does not finish within `timeout`.
+pub async fn with_timeout<Fut>(
+    timeout: Option<std::time::Duration>,
+    method: &str,
+    fut: Fut,
+) -> Result<Vec<u8>>
+where
+    Fut: std::future::Future<Output = Result<Vec<u8>, ic_agent::AgentError>>,
+{
+    match timeout {
+        None => Ok(fut.await?),
+        Some(timeout) => Ok(tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| anyhow!("call to {method} timed out after {timeout:?}"))??),
+    }
+}
+
+/// Run an agent call, retrying transient errors with exponential backoff.
+pub async fn with_retry<F, Fut>(policy: &RetryPolicy, f: F) -> Result<Vec<u8>, ic_agent::AgentError>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<u8>, ic_agent::AgentError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(e) if attempt < policy.max_attempts && is_transient(&e) => {
+                let delay = policy.base_delay * 2u32.saturating_pow(attempt - 1);
+                eprintln!(
+                    "Warning: {e}, retrying in {delay:?} (attempt {attempt}/{})",
+                    policy.max_attempts
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+type HmacSha512 = hmac::Hmac<sha2::Sha512>;
+const HARDENED: u32 = 0x8000_0000;
+
+/// BIP39 seed from a mnemonic with empty passphrase: PBKDF2-HMAC-SHA512, 2048 rounds.
+fn bip39_seed(mnemonic: &str) -> Result<[u8; 64]> {
+    use hmac::Mac;
+    let prf = HmacSha512::new_from_slice(mnemonic.as_bytes())?;
+    let mut u = prf
+        .clone()
+        .chain_update(b"mnemonic\0\0\0\x01")
+        .finalize()
+        .into_bytes();
+    let mut seed = u;
+    for _ in 1..2048 {
+        u = prf.clone().chain_update(u).finalize().into_bytes();
+        seed.iter_mut().zip(u.iter()).for_each(|(s, u)| *s ^= u);
+    }
+    Ok(seed.into())
+}
+
+/// BIP32 private key derivation. Indices with the high bit set are hardened.
+fn bip32_derive(seed: &[u8], path: &[u32]) -> Result<k256::SecretKey> {
+    use hmac::Mac;
+    use k256::elliptic_curve::sec1::ToEncodedPoint;
+    let split = |i: &[u8]| -> Result<(k256::Scalar, [u8; 32])> {
+        let key = k256::FieldBytes::from_slice(&i[..32]);
+        let key = Option::<k256::NonZeroScalar>::from(k256::NonZeroScalar::from_repr(*key))
+            .ok_or_else(|| anyhow!("invalid derived key"))?;
+        Ok((*key, i[32..].try_into()?))
+    };
+    let master = HmacSha512::new_from_slice(b"Bitcoin seed")?
+        .chain_update(seed)
+        .finalize()
+        .into_bytes();
+    let (mut key, mut chain) = split(&master)?;
+    for index in path {
+        let mut mac = HmacSha512::new_from_slice(&chain)?;
+        if index & HARDENED != 0 {
+            mac.update(&[0]);
+            mac.update(&key.to_bytes());
+        } else {
+            let public = k256::ProjectivePoint::GENERATOR * key;
+            mac.update(public.to_affine().to_encoded_point(true).as_bytes());
+        }
+        mac.update(&index.to_be_bytes());
+        let (tweak, child_chain) = split(&mac.finalize().into_bytes())?;
+        key += tweak;
+        chain = child_chain;
+    }
+    Ok(k256::SecretKey::from_bytes(&key.to_bytes())?)
+}
+
+/// Derive a secp256k1 key from a seed phrase the same way dfx does, i.e. m/44'/223'/0'/0/0.
+pub fn seed_to_secret_key(phrase: &str) -> Result<k256::SecretKey> {
+    let words: Vec<_> = phrase.split_whitespace().collect();
+    if ![12, 15, 18, 21, 24].contains(&words.len()) {
+        return Err(anyhow!(
+            "seed phrase should have 12, 15, 18, 21 or 24 words, found {}",
+            words.len()
+        ));
+    }
+    let seed = bip39_seed(&words.join(" "))?;
+    bip32_derive(&seed, &[44 | HARDENED, 223 | HARDENED, HARDENED, 0, 0])
+}
+
+/// Decrypt a password protected PEM file, and build a secp256k1 or ed25519 identity from it.
+pub fn decrypt_pem(pem: &[u8], passphrase: &str) -> Result<Arc<dyn Identity>> {
+    use ic_agent::identity::{BasicIdentity, Secp256k1Identity};
+    use openssl::pkey::{Id, PKey};
+    let key = PKey::private_key_from_pem_passphrase(pem, passphrase.as_bytes())
+        .map_err(|_| anyhow!("cannot decrypt the private key, wrong passphrase?"))?;
+    Ok(match key.id() {
+        Id::EC => {
+            let der = key.ec_key()?.private_key_to_der()?;
+            let key = k256::SecretKey::from_sec1_der(&der)
+                .map_err(|_| anyhow!("only secp256k1 curve is supported"))?;
+            Arc::new(Secp256k1Identity::from_private_key(key))
+        }
+        Id::ED25519 => {
+            let bytes: [u8; 32] = key
+                .raw_private_key()?
+                .try_into()
+                .map_err(|_| anyhow!("invalid ed25519 private key"))?;
+            Arc::new(BasicIdentity::from_signing_key(
+                ed25519_consensus::SigningKey::from(bytes),
+            ))
+        }
+        id => return Err(anyhow!("unsupported key type {id:?}")),
+    })
+}
+
 pub fn get_dfx_hsm_pin() -> Result<String, String> {
     std::env::var("DFX_HSM_PIN").or_else(|_| {
         rpassword::prompt_password("HSM PIN: ")
@@ -262,26 +876,121 @@ pub fn get_dfx_hsm_pin() -> Result<String, String> {
     })
 }
 
-#[tokio::main]
-pub async fn fetch_state_path(agent: &Agent, mut path: StatePath) -> anyhow::Result<IDLValue> {
-    if path.effective_id.is_none() {
-        let id = if path.path.len() >= 3
-            && path.path[0] == "subnet".into()
-            && matches!(path.kind, StateKind::Canister)
-        {
-            get_canister_id_from_subnet(agent, path.path[1].clone()).await.ok_or_else(|| anyhow!("Cannot find any canister on this subnet id. Put the effective canister id as the first argument"))?
-        } else {
-            Principal::from_text(match path.kind {
-                StateKind::Canister => "ryjl3-tyaaa-aaaaa-aaaba-cai",
-                StateKind::Subnet => {
-                    "tdb26-jop6k-aogll-7ltgs-eruif-6kk7m-qpktf-gdiqx-mxtrf-vb5e6-eqe"
-                }
-            })?
-        };
-        path.effective_id = Some(id);
-        eprintln!("Using {id} as effective canister/subnet id. To change it, put the effective id as the first argument.");
-    }
-    fetch_state_path_(agent, path).await
+pub fn fetch_state_path(agent: &Agent, mut path: StatePath) -> anyhow::Result<IDLValue> {
+    runtime().block_on(async move {
+        if path.effective_id.is_none() {
+            let id = if path.path.len() >= 3
+                && path.path[0] == "subnet".into()
+                && matches!(path.kind, StateKind::Canister)
+            {
+                get_canister_id_from_subnet(agent, path.path[1].clone())
+                    .await
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Cannot find any canister on this subnet id. \
+                             Put the effective canister id as the first argument"
+                        )
+                    })?
+            } else {
+                Principal::from_text(match path.kind {
+                    StateKind::Canister => "ryjl3-tyaaa-aaaaa-aaaba-cai",
+                    StateKind::Subnet => {
+                        "tdb26-jop6k-aogll-7ltgs-eruif-6kk7m-qpktf-gdiqx-mxtrf-vb5e6-eqe"
+                    }
+                })?
+            };
+            path.effective_id = Some(id);
+            eprintln!(
+                "Using {id} as effective canister/subnet id. \
+                 To change it, put the effective id as the first argument."
+            );
+        }
+        fetch_state_path_(agent, path).await
+    })
+}
+/// Sign a read_state request for offline use. Returns the call type, the effective id and the signed envelope.
+pub fn sign_state_path(
+    identity: &dyn Identity,
+    path: StatePath,
+) -> anyhow::Result<(String, Principal, Vec<u8>)> {
+    use ic_transport_types::{Envelope, EnvelopeContent};
+    use serde::Serialize;
+    let effective_id = match (path.effective_id, &path.kind) {
+        (Some(id), _) => id,
+        (None, StateKind::Subnet) => {
+            Principal::from_text("tdb26-jop6k-aogll-7ltgs-eruif-6kk7m-qpktf-gdiqx-mxtrf-vb5e6-eqe")?
+        }
+        (None, StateKind::Canister) if path.path.len() >= 3 && path.path[0] == "subnet".into() => {
+            return Err(anyhow!(
+                "Cannot find the effective canister id offline. Put the effective canister id as the first argument"
+            ))
+        }
+        (None, StateKind::Canister) => Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai")?,
+    };
+    let expiry = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?
+        + std::time::Duration::from_secs(4 * 60);
+    let content = EnvelopeContent::ReadState {
+        sender: identity.sender().map_err(|e| anyhow!("{e}"))?,
+        paths: vec![path.path],
+        ingress_expiry: expiry.as_secs() * 1_000_000_000,
+    };
+    let signature = identity.sign(&content).map_err(|e| anyhow!("{e}"))?;
+    let envelope = Envelope {
+        content: Cow::Borrowed(&content),
+        sender_pubkey: signature.public_key,
+        sender_sig: signature.signature,
+        sender_delegation: signature.delegations,
+    };
+    let mut bytes = Vec::new();
+    let mut serializer = serde_cbor::Serializer::new(&mut bytes);
+    serializer.self_describe()?;
+    envelope.serialize(&mut serializer)?;
+    let call_type = match path.kind {
+        StateKind::Subnet => "read_subnet_state",
+        StateKind::Canister => "read_state",
+    };
+    Ok((call_type.to_owned(), effective_id, bytes))
+}
+/// Read the status of an update call, returning
+/// `variant { received; processing; replied : blob; rejected : record { reject_code : nat8; reject_message : text; error_code : opt text }; done; unknown }`.
+pub fn request_status(
+    agent: &Agent,
+    effective_id: Principal,
+    request_id: &[u8],
+) -> Result<IDLValue> {
+    use candid::types::value::VariantValue;
+    use ic_agent::{agent::RequestStatusResponse, RequestId};
+    let request_id = RequestId::new(
+        request_id
+            .try_into()
+            .map_err(|_| anyhow!("request_id should be 32 bytes"))?,
+    );
+    let (status, _) = runtime().block_on(agent.request_status_raw(&request_id, effective_id))?;
+    let (tag, val) = match status {
+        RequestStatusResponse::Unknown => ("unknown", IDLValue::Null),
+        RequestStatusResponse::Received => ("received", IDLValue::Null),
+        RequestStatusResponse::Processing => ("processing", IDLValue::Null),
+        RequestStatusResponse::Done => ("done", IDLValue::Null),
+        RequestStatusResponse::Replied(reply) => ("replied", IDLValue::Blob(reply.arg)),
+        RequestStatusResponse::Rejected(reject) => (
+            "rejected",
+            record(vec![
+                field("reject_code", IDLValue::Nat8(reject.reject_code as u8)),
+                field("reject_message", IDLValue::Text(reject.reject_message)),
+                field(
+                    "error_code",
+                    match reject.error_code {
+                        Some(code) => IDLValue::Opt(Box::new(IDLValue::Text(code))),
+                        None => IDLValue::None,
+                    },
+                ),
+            ]),
+        ),
+    };
+    Ok(IDLValue::Variant(VariantValue(
+        Box::new(field(tag, val)),
+        0,
+    )))
 }
 pub async fn fetch_metadata(
     agent: &Agent,
@@ -302,6 +1011,35 @@ pub async fn fetch_metadata(
         _ => unreachable!(),
     }
 }
+/// Poll the module hash of the canister from the state tree until it equals `expected` or `timeout` expires.
+pub fn wait_module_hash(
+    agent: &Agent,
+    id: Principal,
+    expected: &[u8],
+    timeout: std::time::Duration,
+) -> anyhow::Result<()> {
+    runtime().block_on(async move {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let actual = fetch_metadata(agent, id, "module_hash").await.ok();
+            if actual.as_deref() == Some(expected) {
+                return Ok(());
+            }
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                let actual = actual
+                    .map(hex::encode)
+                    .unwrap_or_else(|| "unavailable".to_string());
+                return Err(anyhow!(
+                    "module hash of {id} is {actual}, expected {} after {timeout:?}",
+                    hex::encode(expected)
+                ));
+            }
+            let interval = std::time::Duration::from_millis(500);
+            tokio::time::sleep(interval.min(deadline - now)).await;
+        }
+    })
+}
 async fn get_canister_id_from_subnet(
     agent: &Agent,
     subnet_id: ic_agent::hash_tree::Label<Vec<u8>>,
@@ -547,3 +1285,174 @@ fn test_cast_type_big_num() {
         );
     }
 }
+
+#[test]
+fn test_icp_e8s() {
+    assert_eq!(e8s_to_icp(123_456_789), "1.23456789");
+    assert_eq!(e8s_to_icp(1), "0.00000001");
+    assert_eq!(icp_to_e8s("1.23456789").unwrap(), 123_456_789);
+    assert_eq!(icp_to_e8s("1_000.5").unwrap(), 100_050_000_000);
+    assert_eq!(icp_to_e8s(".1").unwrap(), 10_000_000);
+    assert_eq!(icp_to_e8s("2").unwrap(), 200_000_000);
+    assert!(icp_to_e8s("0.123456789").is_err());
+    assert!(icp_to_e8s("-1").is_err());
+    assert!(icp_to_e8s(".").is_err());
+    assert!(icp_to_e8s("1e8").is_err());
+}
+
+#[test]
+fn test_int_bytes() {
+    let n = |s: &str| IDLValue::Number(s.to_string());
+    assert_eq!(int_to_bytes(&n("258"), 16, true).unwrap(), vec![2, 1]);
+    assert_eq!(
+        int_to_bytes(&n("258"), 32, false).unwrap(),
+        vec![0, 0, 1, 2]
+    );
+    assert_eq!(int_to_bytes(&n("-1"), 8, true).unwrap(), vec![255]);
+    assert_eq!(
+        int_to_bytes(&n("18446744073709551615"), 64, true).unwrap(),
+        vec![255; 8]
+    );
+    assert!(int_to_bytes(&n("256"), 8, true).is_err());
+    assert!(int_to_bytes(&n("-129"), 8, true).is_err());
+    assert!(int_to_bytes(&n("1"), 24, true).is_err());
+    assert_eq!(bytes_to_int(&[2, 1], true).unwrap(), n("258"));
+    assert_eq!(bytes_to_int(&[0, 0, 1, 2], false).unwrap(), n("258"));
+    assert!(bytes_to_int(&[0, 0, 0], false).is_err());
+}
+
+#[test]
+fn test_leb128() {
+    let n = |s: &str| IDLValue::Number(s.to_string());
+    assert_eq!(
+        leb128_encode(&n("624485"), false).unwrap(),
+        vec![0xe5, 0x8e, 0x26]
+    );
+    assert_eq!(
+        leb128_encode(&n("-123456"), true).unwrap(),
+        vec![0xc0, 0xbb, 0x78]
+    );
+    assert!(leb128_encode(&n("-1"), false).is_err());
+    assert_eq!(
+        leb128_decode(&[0xe5, 0x8e, 0x26], false).unwrap(),
+        n("624485")
+    );
+    assert_eq!(
+        leb128_decode(&[0xc0, 0xbb, 0x78], true).unwrap(),
+        n("-123456")
+    );
+    assert!(leb128_decode(&[0xe5, 0x8e, 0x26, 0], false).is_err());
+    assert!(leb128_decode(&[0xe5], false).is_err());
+}
+
+#[test]
+fn test_cost_diff() -> Result<()> {
+    let parse = |s: &str| match candid_parser::parse_idl_value(s).unwrap() {
+        IDLValue::Record(fs) => fs,
+        _ => unreachable!(),
+    };
+    let before = parse("record { get = 100; put = 200 }");
+    let after = parse("record { get = 90; put = 250; del = 10 }");
+    let expected = candid_parser::parse_idl_value(
+        r#"record {
+          diff = record {
+            get = record { before = 100 : int; after = 90 : int; delta = -10 : int };
+            put = record { before = 200 : int; after = 250 : int; delta = 50 : int };
+            del = record { before = 0 : int; after = 10 : int; delta = 10 : int };
+          };
+        }"#,
+    )?;
+    let IDLValue::Record(fs) = cost_diff(&before, &after)? else {
+        unreachable!()
+    };
+    let IDLValue::Record(expected) = expected else {
+        unreachable!()
+    };
+    assert_eq!(get_field(&fs, "diff"), get_field(&expected, "diff"));
+    let Some(IDLValue::Vec(regressions)) = get_field(&fs, "regressions") else {
+        unreachable!()
+    };
+    let mut names: Vec<_> = regressions
+        .iter()
+        .map(|v| match v {
+            IDLValue::Text(name) => name.as_str(),
+            _ => unreachable!(),
+        })
+        .collect();
+    names.sort_unstable();
+    assert_eq!(names, ["del", "put"]);
+    let Some(IDLValue::Record(diff)) = get_field(&fs, "diff") else {
+        unreachable!()
+    };
+    let delta = |name: &str| match get_field(diff, name) {
+        Some(IDLValue::Record(d)) => get_field(d, "delta").cloned(),
+        _ => None,
+    };
+    assert_eq!(delta("put"), Some(IDLValue::Int(50.into())));
+    assert_eq!(delta("del"), Some(IDLValue::Int(10.into())));
+    assert_eq!(delta("get"), Some(IDLValue::Int((-10).into())));
+    Ok(())
+}
+
+#[test]
+fn test_merkle_proof() -> Result<()> {
+    use sha2::{Digest, Sha256};
+    let leaves: Vec<Vec<u8>> = (0u8..5).map(|i| vec![i]).collect();
+    let root = merkle_root(&leaves)?;
+    for (i, leaf) in leaves.iter().enumerate() {
+        let mut hash = Sha256::digest(leaf).to_vec();
+        for (sibling, is_left) in merkle_proof(&leaves, i)? {
+            let mut hasher = Sha256::new();
+            if is_left {
+                hasher.update(&sibling);
+                hasher.update(&hash);
+            } else {
+                hasher.update(&hash);
+                hasher.update(&sibling);
+            }
+            hash = hasher.finalize().to_vec();
+        }
+        assert_eq!(hash, root);
+    }
+    assert!(merkle_proof(&leaves, 5).is_err());
+    assert!(merkle_root(&[]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_seed_derivation() -> Result<()> {
+    let mnemonic = format!("{} about", ["abandon"; 11].join(" "));
+    let seed = bip39_seed(&mnemonic)?;
+    assert_eq!(hex::encode(seed), "5eb00bbddcf069084889a8ab9155568165f5c453ccb85e70811aaed6f6da5fc19a5ac40b389cd370d086206dec8aa6c43daea6690f20ad3d8d48b2d2ce9e38e4");
+    // BIP32 test vector 1: m/0'/1
+    let seed = hex::decode("000102030405060708090a0b0c0d0e0f")?;
+    let key = bip32_derive(&seed, &[HARDENED, 1])?;
+    assert_eq!(
+        hex::encode(key.to_bytes()),
+        "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368"
+    );
+    Ok(())
+}
+
+#[test]
+fn test_decrypt_pem() -> Result<()> {
+    use openssl::{pkey::PKey, symm::Cipher};
+    let key = PKey::generate_ed25519()?;
+    let pem = key.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), b"secret")?;
+    assert!(decrypt_pem(&pem, "wrong").is_err());
+    let id = decrypt_pem(&pem, "secret")?;
+    let expected = ic_agent::identity::BasicIdentity::from_signing_key(
+        ed25519_consensus::SigningKey::from(<[u8; 32]>::try_from(key.raw_private_key()?).unwrap()),
+    );
+    assert_eq!(id.sender(), expected.sender());
+    Ok(())
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("neuron_*", "neuron_1"));
+    assert!(glob_match("*_id", "canister_id"));
+    assert!(glob_match("a?c*", "abc"));
+    assert!(!glob_match("neuron_*", "my_neuron_1"));
+    assert!(!glob_match("a?c", "ac"));
+}